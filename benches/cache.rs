@@ -0,0 +1,88 @@
+//! 决策缓存吞吐基准：多线程并发 get/put，对比不同分片数。
+//!
+//! cargo bench --bench cache [-- <线程数> <每线程操作数>]
+
+#[path = "../src/lru.rs"]
+#[allow(dead_code)]
+mod lru;
+
+use std::sync::{Arc, Barrier};
+use std::time::Instant;
+
+use lru::{Cache, CacheDecision, Lookup};
+
+const CAPACITY: usize = 3000;
+const MAX_BYTES: usize = 1024 * 1024;
+// 键空间略大于容量，保持一定的未命中和淘汰
+const KEY_SPACE: usize = 4000;
+const SHARD_COUNTS: &[usize] = &[1, 2, 4, 8, 16, 32, 64];
+
+fn main() {
+    // cargo bench 会附加 --bench，忽略非数字参数
+    let mut nums = std::env::args().skip(1).filter_map(|a| a.parse::<usize>().ok());
+    let threads = nums
+        .next()
+        .unwrap_or_else(|| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+    let ops = nums.next().unwrap_or(1_000_000);
+
+    let keys: Arc<Vec<String>> = Arc::new(
+        (0..KEY_SPACE)
+            .map(|i| format!("Mozilla/5.0 (Linux; Android 14; Device-{i}) AppleWebKit/537.36 Chrome/126.0 Mobile"))
+            .collect(),
+    );
+
+    println!("threads={threads} ops/thread={ops} capacity={CAPACITY} keys={KEY_SPACE} budget={MAX_BYTES}");
+    println!("{:>8} {:>8} {:>12} {:>10} {:>8} {:>10}", "verify", "shards", "ops/s", "elapsed", "hit%", "bytes");
+    for verify in [false, true] {
+        for &shards in SHARD_COUNTS {
+            let cache = Arc::new(Cache::new(CAPACITY, shards, MAX_BYTES, verify));
+            let (elapsed, hits) = run(&cache, threads, ops, &keys);
+            let total = (threads * ops) as f64;
+            println!(
+                "{:>8} {:>8} {:>12.0} {:>9.3}s {:>7.1}% {:>10}",
+                verify,
+                cache.shard_count(),
+                total / elapsed,
+                elapsed,
+                hits as f64 * 100.0 / total,
+                cache.bytes(),
+            );
+        }
+    }
+}
+
+/// 模拟 HttpHandler 的访问模式：先 get，未命中再 put
+fn run(cache: &Arc<Cache>, threads: usize, ops: usize, keys: &Arc<Vec<String>>) -> (f64, usize) {
+    let barrier = Arc::new(Barrier::new(threads + 1));
+
+    let workers: Vec<_> = (0..threads)
+        .map(|t| {
+            let cache = cache.clone();
+            let keys = keys.clone();
+            let barrier = barrier.clone();
+            std::thread::spawn(move || {
+                // 每线程独立的 xorshift，避免共享随机源
+                let mut x = 0x9E37_79B9_7F4A_7C15u64 ^ (t as u64 + 1);
+                let mut hits = 0;
+                barrier.wait();
+                for _ in 0..ops {
+                    x ^= x << 13;
+                    x ^= x >> 7;
+                    x ^= x << 17;
+                    let key = &keys[x as usize % keys.len()];
+                    if let Lookup::Hit(_) = cache.get(key) {
+                        hits += 1;
+                    } else {
+                        cache.put(key, CacheDecision::Modify, None);
+                    }
+                }
+                hits
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    let started = Instant::now();
+    let hits = workers.into_iter().map(|w| w.join().unwrap()).sum();
+    (started.elapsed().as_secs_f64(), hits)
+}
@@ -6,7 +6,7 @@ local CONFIG_NAME = "uaforge"
 local SERVICE_NAME = "uaforge"
 local INIT_SCRIPT = "/etc/init.d/" .. SERVICE_NAME
 local BIN_PATH = "/usr/bin/" .. SERVICE_NAME
-local stats_file = "/tmp/uaforge.stats"
+local stats_file = uci:get(CONFIG_NAME, "main", "stats_file") or "/tmp/uaforge.stats"
 
 -- Cache for reducing fork overhead
 local cache = {
@@ -68,17 +68,43 @@ local function get_stats()
         return {}
     end
 
+    local content = f:read("*a") or ""
+    f:close()
+
     local stats = {}
-    for line in f:lines() do
+    if content:match("^%s*{") then
+        -- JSON 格式（stats_format = json）
+        local ok, jsonc = pcall(require, "luci.jsonc")
+        local obj = ok and jsonc.parse(content)
+        if type(obj) == "table" then
+            for key, val in pairs(obj) do
+                stats[key] = type(val) == "number" and string.format(math.floor(val) == val and "%d" or "%.2f", val) or tostring(val)
+            end
+        end
+        return stats
+    end
+
+    for line in content:gmatch("[^\n]+") do
         local key, val = line:match("([^:]+):(.*)")
         if key and val then
             stats[key] = val
         end
     end
-    f:close()
     return stats
 end
 
+-- 将字节数格式化为易读的单位
+local function format_bytes(n)
+    n = tonumber(n) or 0
+    local units = { "B", "KB", "MB", "GB", "TB" }
+    local i = 1
+    while n >= 1024 and i < #units do
+        n = n / 1024
+        i = i + 1
+    end
+    return string.format(i == 1 and "%d %s" or "%.2f %s", n, units[i])
+end
+
 -- 辅助函数，用于从缓存中获取特定值
 local function get_stat_value(key)
     local stats = get_stats()
@@ -142,15 +168,55 @@ stats_display.cfgvalue = function(self, section)
     local cache_mod   = stats["cache_hit_modify"] or "0"
     local cache_pass  = stats["cache_hit_pass"] or "0"
     local cache_ratio = stats["total_cache_ratio"] or "0.00"
+    local cache_exp   = stats["cache_expired"] or "0"
 
-    return string.format(
+    local out = string.format(
         "<b>当前连接:</b> %s | <b>请求总数:</b> %s | <b>处理速率:</b> %s RPS<br>" ..
         "<b>成功修改:</b> %s | <b>直接放行:</b> %s | <b>规则处理:</b> %s<br>" ..
-        "<b>缓存(修改):</b> %s | <b>缓存(放行):</b> %s | <b>总缓存率:</b> %s%%",
+        "<b>缓存(修改):</b> %s | <b>缓存(放行):</b> %s | <b>总缓存率:</b> %s%% | <b>缓存过期:</b> %s",
         connections, total_reqs, rps,
         modified, passthrough, rule_proc,
-        cache_mod, cache_pass, cache_ratio
+        cache_mod, cache_pass, cache_ratio, cache_exp
+    )
+
+    -- 第四行：流量与异常
+    out = out .. string.format(
+        "<br><b>上行:</b> %s | <b>下行:</b> %s | <b>非 HTTP 连接:</b> %s | <b>上游连接失败:</b> %s | <b>HTTP 解析错误:</b> %s | <b>回环拒绝:</b> %s",
+        format_bytes(stats["bytes_up"]), format_bytes(stats["bytes_down"]),
+        stats["non_http_connections"] or "0",
+        stats["upstream_connect_failures"] or "0",
+        stats["http_parse_errors"] or "0",
+        stats["loops_refused"] or "0"
+    )
+
+    -- 第五行：白名单与防火墙
+    out = out .. string.format(
+        "<br><b>UA 白名单:</b> %s | <b>防火墙白名单:</b> %s | <b>断开连接:</b> %s | <b>卸载条目:</b> %s | <b>写入失败:</b> %s",
+        stats["whitelist_hits"] or "0",
+        stats["fw_whitelist_hits"] or "0",
+        stats["fw_drops"] or "0",
+        stats["fw_offload_added"] or "0",
+        stats["fw_batch_failures"] or "0"
     )
+
+    -- 第六行：延迟（毫秒，p50 / p99）
+    out = out .. string.format(
+        "<br><b>上游建连:</b> %s / %s ms | <b>UA 处理:</b> %s / %s ms | <b>首字节:</b> %s / %s ms | <b>总耗时:</b> %s / %s ms",
+        stats["latency_connect_p50_ms"] or "0", stats["latency_connect_p99_ms"] or "0",
+        stats["latency_modify_p50_ms"] or "0", stats["latency_modify_p99_ms"] or "0",
+        stats["latency_ttfb_p50_ms"] or "0", stats["latency_ttfb_p99_ms"] or "0",
+        stats["latency_total_p50_ms"] or "0", stats["latency_total_p99_ms"] or "0"
+    )
+
+    -- 第七行：演练模式（仅启用时显示）
+    if uci:get(CONFIG_NAME, "main", "firewall_dry_run") == "1" then
+        out = out .. string.format(
+            "<br><b>演练卸载(非HTTP):</b> %s | <b>演练卸载(UA白名单):</b> %s",
+            stats["fw_dry_run_nonhttp"] or "0",
+            stats["fw_dry_run_ua_whitelist"] or "0"
+        )
+    end
+    return out
 end
 
 main:tab("general", "常规设置")
@@ -178,7 +244,26 @@ cache_size = main:taboption("general", Value, "cache_size", "LRU 缓存大小")
 cache_size:depends("operating_profile", "custom")
 cache_size.datatype = "uinteger"
 cache_size.default = "1000"
-cache_size.description = "LRU 缓存大小。缓存更大命中率更高，预估每 1000 条占用约 300KB 内存。"
+cache_size.description = "LRU 缓存大小。缓存更大命中率更高，按 UA 哈希存储，预估每 1000 条占用约 100KB 内存。"
+
+cache_shards = main:taboption("advanced", Value, "cache_shards", "缓存分片数")
+cache_shards.datatype = "uinteger"
+cache_shards.default = 0
+cache_shards.description = "决策缓存按 UA 哈希分片、各自加锁，多核并发时减少锁竞争。0 表示自动（单核 1 片，多核每核 4 片）。"
+
+cache_max_bytes = main:taboption("advanced", Value, "cache_max_bytes", "缓存内存上限")
+cache_max_bytes.default = "1M"
+cache_max_bytes.placeholder = "1M"
+cache_max_bytes.description = "决策缓存的估算内存上限，支持 K/M 后缀，超出时淘汰最久未用的条目。0 表示只按条目数限制。"
+
+cache_verify = main:taboption("advanced", Flag, "cache_verify", "缓存保存完整 UA")
+cache_verify.default = "0"
+cache_verify.description = "缓存默认只保存 UA 的 64 位哈希。开启后额外保存 UA 原文并在命中时比对，内存占用随 UA 长度增加，由缓存内存上限约束。"
+
+cache_ttl = main:taboption("advanced", Value, "cache_ttl", "缓存决策有效期")
+cache_ttl.default = "0"
+cache_ttl.placeholder = "10m"
+cache_ttl.description = "改写 / 放行决策的有效期（如 600s、10m、1h），过期后重新匹配规则。0 表示只随 LRU 淘汰。防火墙白名单决策始终随防火墙超时过期，以便重新卸载。"
 
 ua = main:taboption("general", Value, "ua", "User-Agent 标识")
 ua.default = "FFF"
@@ -214,6 +299,73 @@ port = main:taboption("network", Value, "port", "监听端口")
 port.default = "12032"
 port.datatype = "port"
 
+mode = main:taboption("network", ListValue, "mode", "工作模式")
+mode:value("transparent", "透明代理（防火墙重定向）")
+mode:value("forward", "显式 HTTP 代理")
+mode.default = "transparent"
+mode.description = "显式 HTTP 代理模式下客户端需将代理设置为 路由器地址:监听端口，支持 CONNECT 隧道，不设置重定向规则。"
+
+forward_allow = main:taboption("network", Value, "forward_allow", "显式代理允许来源")
+forward_allow.placeholder = "127.0.0.0/8,10.0.0.0/8,172.16.0.0/12,192.168.0.0/16,::1/128,fc00::/7"
+forward_allow.description = "允许使用显式 HTTP 代理的来源网段（逗号分隔），其余来源返回 403。留空使用默认的本机与局域网网段。"
+forward_allow:depends("mode", "forward")
+
+forward_user = main:taboption("network", Value, "forward_user", "显式代理用户名")
+forward_user.description = "设置后客户端需提供 Basic 代理认证，否则返回 407。留空表示不需要认证。"
+forward_user:depends("mode", "forward")
+
+forward_pass = main:taboption("network", Value, "forward_pass", "显式代理密码")
+forward_pass.password = true
+forward_pass:depends("mode", "forward")
+
+socks5_listen = main:taboption("network", Value, "socks5_listen", "SOCKS5 监听地址")
+socks5_listen.placeholder = "0.0.0.0:1080"
+socks5_listen.description = "在主端口之外额外提供 SOCKS5（CONNECT）入口，可供 Clash/OpenClash 链式代理，HTTP 流量同样改写 UA。留空表示不启用。"
+
+socks5_user = main:taboption("network", Value, "socks5_user", "SOCKS5 用户名")
+socks5_user.description = "留空表示不需要认证。"
+
+socks5_pass = main:taboption("network", Value, "socks5_pass", "SOCKS5 密码")
+socks5_pass.password = true
+
+upstream_proxy = main:taboption("network", Value, "upstream_proxy", "上游代理")
+upstream_proxy.placeholder = "socks5://127.0.0.1:7891"
+upstream_proxy.description = "出站连接经上游代理建立，支持 socks5://[用户:密码@]主机:端口 与 http://[用户:密码@]主机:端口（CONNECT）。可将 UAForge 置于代理核心之前，无需按 GID 绕过。留空表示直连。"
+
+upstream_direct = main:taboption("network", Value, "upstream_direct", "直连网段")
+upstream_direct.placeholder = "127.0.0.0/8,10.0.0.0/8,172.16.0.0/12,192.168.0.0/16"
+upstream_direct.description = "目标在这些网段（逗号分隔）内时不经上游代理。留空使用默认的本机、局域网与链路本地网段。"
+
+upstream_mark = main:taboption("network", Value, "upstream_mark", "出站连接标记")
+upstream_mark.placeholder = "0x100"
+upstream_mark.description = "为所有出站连接设置 SO_MARK（十进制或 0x 十六进制），可配合策略路由或防火墙规则使用，本机流量重定向时也按此标记豁免。留空表示不设置。"
+
+upstream_interface = main:taboption("network", Value, "upstream_interface", "出站接口")
+upstream_interface.placeholder = "wan"
+upstream_interface.description = "出站连接绑定到指定网络设备（SO_BINDTODEVICE），如 pppoe-wan。留空表示由路由表决定。"
+
+upstream_source = main:taboption("network", Value, "upstream_source", "出站源地址")
+upstream_source.datatype = "ipaddr"
+upstream_source.description = "出站连接使用的源地址，仅作用于同一地址族的目标。留空表示由系统选择。"
+
+proxy_protocol = main:taboption("network", Flag, "proxy_protocol", "接收 PROXY 协议")
+proxy_protocol.default = "0"
+proxy_protocol.description = "UAForge 位于 HAProxy 或代理核心之后时启用。来自可信网段的透明代理连接必须以 PROXY 协议 v1/v2 头开头，并使用其中的来源和目标地址。"
+
+proxy_protocol_trusted = main:taboption("network", Value, "proxy_protocol_trusted", "PROXY 协议可信网段")
+proxy_protocol_trusted:depends("proxy_protocol", "1")
+proxy_protocol_trusted.default = "127.0.0.0/8,::1/128"
+proxy_protocol_trusted.description = "只解析来自这些网段（逗号分隔）的 PROXY 协议头，其他来源按普通透明代理连接处理。"
+
+error_body = main:taboption("network", Value, "error_body", "上游错误提示")
+error_body.placeholder = "UAForge: upstream unreachable"
+error_body.description = "上游连接或请求失败时，UAForge 返回 502 / 504 响应并关闭连接，此处为响应正文（最长 1024 字节）。留空时使用状态行，如 502 Bad Gateway。"
+
+upstream_timeout = main:taboption("network", Value, "upstream_timeout", "上游响应超时（秒）")
+upstream_timeout.datatype = "uinteger"
+upstream_timeout.default = 0
+upstream_timeout.description = "请求发出后等待上游响应头的最长时间，超时返回 504。0 表示不限制。"
+
 iface = main:taboption("network", Value, "iface", "监听接口")
 iface.default = "br-lan"
 iface.description = "指定监听的 LAN 口。"
@@ -235,6 +387,34 @@ Firewall_drop_on_match=main:taboption("network", Flag, "Firewall_drop_on_match",
 Firewall_drop_on_match:depends("enable_firewall_set", "1")
 Firewall_drop_on_match.description = "启用后，当流量匹配 UA 白名单规则时，将直接断开连接，强制其重新建立连接绕过 UAForge。"
 
+firewall_drop_action = main:taboption("network", ListValue, "firewall_drop_action", "断开方式")
+firewall_drop_action:depends("Firewall_drop_on_match", "1")
+firewall_drop_action:value("rst", "立即重置连接 (RST)")
+firewall_drop_action:value("503", "返回 503 并要求重试")
+firewall_drop_action:value("redirect", "重定向到同一地址 (307)")
+firewall_drop_action:value("pass", "本次照常转发，响应后关闭")
+firewall_drop_action.default = "rst"
+firewall_drop_action.description = "匹配后如何结束当前连接。RST 最快但浏览器可能显示错误页；重定向会让浏览器自动重新请求，新连接直接走防火墙绕过。"
+
+firewall_offload_key = main:taboption("network", ListValue, "firewall_offload_key", "卸载粒度")
+firewall_offload_key:depends("enable_firewall_set", "1")
+firewall_offload_key:value("ip-port", "目标 IP + 端口（默认）")
+firewall_offload_key:value("ip", "仅目标 IP")
+firewall_offload_key:value("prefix", "目标网段")
+firewall_offload_key:value("tuple", "源 IP + 目标 IP + 端口")
+firewall_offload_key.default = "ip-port"
+firewall_offload_key.description = "决定卸载条目和非 HTTP 计分按什么聚合。CDN 服务跨整个网段时可选“目标网段”；P2P 端口随机但对端固定时可选“仅目标 IP”。"
+
+firewall_prefix_len = main:taboption("network", Value, "firewall_prefix_len", "网段前缀长度")
+firewall_prefix_len:depends("firewall_offload_key", "prefix")
+firewall_prefix_len.datatype = "range(1,32)"
+firewall_prefix_len.default = "24"
+
+firewall_state_file = main:taboption("network", Value, "firewall_state_file", "决策状态文件")
+firewall_state_file:depends("enable_firewall_set", "1")
+firewall_state_file.placeholder = "/tmp/uaforge/fw.state"
+firewall_state_file.description = "定期保存已卸载条目和决策器状态，重启服务后自动恢复。留空则不保存。"
+
 proxy_host = main:taboption("network", Flag, "proxy_host", "代理主机流量")
 proxy_host.description = "启用后将代理主机自身的流量。如果需要尽量避免和其他代理冲突，请禁用此选项。"
 proxy_host.default = 0
@@ -270,7 +450,19 @@ firewall_nonhttp_threshold = main:taboption("advanced", Value, "firewall_nonhttp
 firewall_nonhttp_threshold:depends("firewall_advanced_settings", "1")
 firewall_nonhttp_threshold.datatype = "uinteger"
 firewall_nonhttp_threshold.default = 5
-firewall_nonhttp_threshold.description = "在将一个 IP+端口 确认为非 HTTP 流量之前，需要连续检测到的非 HTTP 连接次数。"
+firewall_nonhttp_threshold.description = "在将一个 IP+端口 确认为非 HTTP 流量之前，统计窗口内需要检测到的非 HTTP 连接次数。"
+
+firewall_score_window = main:taboption("advanced", Value, "firewall_score_window", "统计窗口（秒）")
+firewall_score_window:depends("firewall_advanced_settings", "1")
+firewall_score_window.datatype = "uinteger"
+firewall_score_window.default = 600
+firewall_score_window.description = "只统计最近这段时间内的连接，分散在很长时间里的零星非 HTTP 连接不会触发卸载。单位为秒。"
+
+firewall_nonhttp_ratio = main:taboption("advanced", Value, "firewall_nonhttp_ratio", "非 HTTP 最小占比")
+firewall_nonhttp_ratio:depends("firewall_advanced_settings", "1")
+firewall_nonhttp_ratio.datatype = "range(0,1)"
+firewall_nonhttp_ratio.default = 0
+firewall_nonhttp_ratio.description = "统计窗口内非 HTTP 连接占全部连接的比例（0-1）达到该值才会卸载，用于避免误卸载混合协议端口。0 表示不限制。"
 
 firewall_decision_delay = main:taboption("advanced", Value, "firewall_decision_delay", "决策延迟时间（秒）")
 firewall_decision_delay:depends("firewall_advanced_settings", "1")
@@ -285,6 +477,20 @@ firewall_timeout.default = 28800
 firewall_timeout.description = "添加到 ipset/nfset 中的规则的超时时间。单位为秒（默认8*3600）。"
 
 
+firewall_dry_run = main:taboption("advanced", Flag, "firewall_dry_run", "演练模式")
+firewall_dry_run:depends("enable_firewall_set", "1")
+firewall_dry_run.default = 0
+firewall_dry_run.description = "启用后，决策器只在日志和运行统计中记录本应卸载的 IP+端口 及原因，不写入 ipset/nfset。用于在生产环境中评估上述参数。"
+
+admin_listen = main:taboption("advanced", Value, "admin_listen", "管理接口地址")
+admin_listen.placeholder = "127.0.0.1:12033"
+admin_listen.description = "本地管理 HTTP 接口，可查询状态、重载规则、清空缓存、管理卸载条目和修改日志级别。仅允许回环地址，留空禁用（默认）。"
+
+admin_token = main:taboption("advanced", Value, "admin_token", "管理接口令牌")
+admin_token.password = true
+admin_token.description = "设置后，管理接口的每个请求都需携带 Authorization: Bearer &lt;令牌&gt; 头。路由器上运行其他服务或用户时建议设置。"
+
+
 -- === Tab 4: 应用日志 ===
 
 
@@ -299,6 +505,52 @@ log_file = main:taboption("softlog", Value, "log_file", "应用日志路径")
 log_file.placeholder = "/tmp/uaforge/uaforge.log"
 log_file.description = "指定 Rust 程序运行时日志的输出文件路径。留空将禁用文件日志。"
 
+log_modules = main:taboption("softlog", Value, "log_modules", "调试日志模块")
+log_modules:depends("log_level", "debug")
+log_modules.placeholder = "handler,firewall"
+log_modules.description = "只输出这些模块的调试日志（server、handler、firewall、admin，逗号分隔）。留空输出全部。"
+
+log_clients = main:taboption("softlog", Value, "log_clients", "调试日志客户端")
+log_clients:depends("log_level", "debug")
+log_clients.placeholder = "192.168.1.50"
+log_clients.description = "只输出这些客户端 IP 的调试日志（逗号分隔），便于在繁忙的路由器上排查单个设备。留空输出全部。"
+
+stats_format = main:taboption("softlog", ListValue, "stats_format", "统计文件格式")
+stats_format.default = "kv"
+stats_format:value("kv", "key:value")
+stats_format:value("json", "JSON（含运行时长、内存、文件描述符）")
+stats_format:value("off", "关闭")
+stats_format.description = "关闭后本页面不再显示运行统计。"
+
+stats_file = main:taboption("softlog", Value, "stats_file", "统计文件路径")
+stats_file.placeholder = "/tmp/uaforge.stats"
+stats_file:depends("stats_format", "kv")
+stats_file:depends("stats_format", "json")
+
+stats_interval = main:taboption("softlog", Value, "stats_interval", "统计写入间隔（秒）")
+stats_interval.datatype = "range(1,3600)"
+stats_interval.default = 5
+stats_interval:depends("stats_format", "kv")
+stats_interval:depends("stats_format", "json")
+
+top_capacity = main:taboption("softlog", Value, "top_capacity", "高频条目跟踪数")
+top_capacity.datatype = "uinteger"
+top_capacity.default = 100
+top_capacity.description = "每类（被改写的 UA、放行的 UA、Host、客户端、非 HTTP 卸载条目）最多跟踪的条目数，内存固定。0 表示禁用。"
+
+top_file = main:taboption("softlog", Value, "top_file", "高频条目报告文件")
+top_file.placeholder = "/tmp/uaforge.top"
+top_file.description = "随统计文件定期写出各类前 20 名，每行为 “次数 误差 条目”。留空不写文件（仍可通过管理接口 /top 查询）。"
+
+client_stats_capacity = main:taboption("softlog", Value, "client_stats_capacity", "客户端统计数量")
+client_stats_capacity.datatype = "uinteger"
+client_stats_capacity.default = 256
+client_stats_capacity.description = "按源 IP 统计请求、改写、流量和活动连接的最大客户端数，表满时淘汰最久未活动的客户端。0 表示禁用。"
+
+client_stats_file = main:taboption("softlog", Value, "client_stats_file", "客户端统计文件")
+client_stats_file.placeholder = "/tmp/uaforge.clients"
+client_stats_file.description = "随统计文件定期写出每个客户端的 IP、MAC、活动连接、请求、改写、非 HTTP 连接、上下行字节和空闲秒数。留空不写文件（仍可通过管理接口 /clients 查询）。"
+
 -- Helper function to read last N lines without fork
 local function read_last_lines(filepath, max_lines)
     local f = io.open(filepath, "r")
@@ -0,0 +1,307 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::time::Instant;
+
+use parking_lot::Mutex;
+
+const ARP_TABLE: &str = "/proc/net/arp";
+// 每个分片至少容纳的客户端数，分片过小时淘汰来得过早
+const MIN_SHARD_CAPACITY: usize = 16;
+
+/// 单个客户端的计数
+#[derive(Clone, Debug)]
+struct ClientEntry {
+    active: usize,
+    requests: u64,
+    modified: u64,
+    non_http: u64,
+    bytes_up: u64,
+    bytes_down: u64,
+    last_seen: Instant,
+}
+
+impl ClientEntry {
+    fn new(now: Instant) -> Self {
+        Self {
+            active: 0,
+            requests: 0,
+            modified: 0,
+            non_http: 0,
+            bytes_up: 0,
+            bytes_down: 0,
+            last_seen: now,
+        }
+    }
+}
+
+/// 按源 IP 统计的客户端表，最多 `capacity` 个客户端。
+///
+/// 按 IP 哈希分片，每个分片各自加锁，不同客户端的请求互不阻塞；容量在分片间均分。
+/// 分片满时淘汰其中最久未活动且没有活动连接的客户端；全部都有活动连接时新客户端不计入
+/// （全局统计不受影响）。MAC 地址只在输出时从 ARP 表解析，不占用常驻内存。
+pub struct ClientTable {
+    shard_capacity: usize,
+    shards: Box<[Mutex<HashMap<IpAddr, ClientEntry>>]>,
+    hasher: RandomState,
+}
+
+impl ClientTable {
+    /// capacity 为 0 表示禁用
+    pub fn new(capacity: usize) -> Self {
+        Self::with_shards(capacity, shard_count(capacity))
+    }
+
+    fn with_shards(capacity: usize, shards: usize) -> Self {
+        Self {
+            shard_capacity: capacity.div_ceil(shards),
+            shards: (0..shards).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub fn connect(&self, ip: IpAddr) {
+        self.update(ip, |e| e.active += 1);
+    }
+
+    pub fn disconnect(&self, ip: IpAddr) {
+        if self.shard_capacity == 0 {
+            return;
+        }
+        if let Some(e) = self.shard(ip).lock().get_mut(&ip) {
+            e.active = e.active.saturating_sub(1);
+            e.last_seen = Instant::now();
+        }
+    }
+
+    pub fn inc_requests(&self, ip: IpAddr) {
+        self.update(ip, |e| e.requests += 1);
+    }
+
+    pub fn inc_modified(&self, ip: IpAddr) {
+        self.update(ip, |e| e.modified += 1);
+    }
+
+    pub fn inc_non_http(&self, ip: IpAddr) {
+        self.update(ip, |e| e.non_http += 1);
+    }
+
+    pub fn add_bytes(&self, ip: IpAddr, up: u64, down: u64) {
+        self.update(ip, |e| {
+            e.bytes_up += up;
+            e.bytes_down += down;
+        });
+    }
+
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.lock().retain(|_, e| e.active > 0);
+        }
+    }
+
+    fn shard(&self, ip: IpAddr) -> &Mutex<HashMap<IpAddr, ClientEntry>> {
+        // 分片数为 2 的幂
+        let idx = self.hasher.hash_one(ip) as usize & (self.shards.len() - 1);
+        &self.shards[idx]
+    }
+
+    fn update(&self, ip: IpAddr, f: impl FnOnce(&mut ClientEntry)) {
+        if self.shard_capacity == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.shard(ip).lock();
+        if !entries.contains_key(&ip) && entries.len() >= self.shard_capacity {
+            // 只扫描一个分片，且只在新客户端遇到满分片时发生
+            let idle = entries
+                .iter()
+                .filter(|(_, e)| e.active == 0)
+                .min_by_key(|(_, e)| e.last_seen)
+                .map(|(ip, _)| *ip);
+            match idle {
+                Some(victim) => {
+                    entries.remove(&victim);
+                }
+                None => return,
+            }
+        }
+        let entry = entries.entry(ip).or_insert_with(|| ClientEntry::new(now));
+        entry.last_seen = now;
+        f(entry);
+    }
+
+    /// 每行一个客户端，按请求数降序：
+    /// `<ip> <mac|-> <活动连接> <请求> <改写> <非 HTTP 连接> <上行字节> <下行字节> <空闲秒>`
+    pub fn render(&self) -> String {
+        let arp = read_arp_table();
+        let now = Instant::now();
+        let mut rows: Vec<(IpAddr, ClientEntry)> = self
+            .shards
+            .iter()
+            .flat_map(|s| s.lock().iter().map(|(ip, e)| (*ip, e.clone())).collect::<Vec<_>>())
+            .collect();
+        rows.sort_by(|a, b| b.1.requests.cmp(&a.1.requests).then_with(|| a.0.cmp(&b.0)));
+
+        let mut out = String::from(
+            "# ip mac active requests modified non_http bytes_up bytes_down idle_secs\n",
+        );
+        for (ip, e) in rows {
+            let _ = writeln!(
+                out,
+                "{} {} {} {} {} {} {} {} {}",
+                ip,
+                arp.get(&ip).map(String::as_str).unwrap_or("-"),
+                e.active,
+                e.requests,
+                e.modified,
+                e.non_http,
+                e.bytes_up,
+                e.bytes_down,
+                now.saturating_duration_since(e.last_seen).as_secs(),
+            );
+        }
+        out
+    }
+}
+
+fn read_arp_table() -> HashMap<IpAddr, String> {
+    fs::read_to_string(ARP_TABLE).map(|c| parse_arp(&c)).unwrap_or_default()
+}
+
+/// 解析 /proc/net/arp：`IP address  HW type  Flags  HW address  Mask  Device`
+fn parse_arp(content: &str) -> HashMap<IpAddr, String> {
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let ip = fields.first()?.parse::<IpAddr>().ok()?;
+            let mac = *fields.get(3)?;
+            // 未完成解析的条目 MAC 为全零
+            (mac != "00:00:00:00:00:00").then(|| (ip, mac.to_string()))
+        })
+        .collect()
+}
+
+/// 每核一个分片（向上取整为 2 的幂），且每个分片不少于 MIN_SHARD_CAPACITY 个客户端
+fn shard_count(capacity: usize) -> usize {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut shards = cores.next_power_of_two();
+    while shards > 1 && capacity / shards < MIN_SHARD_CAPACITY {
+        shards /= 2;
+    }
+    shards
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 168, 1, last))
+    }
+
+    fn clients(table: &ClientTable) -> Vec<IpAddr> {
+        let mut ips: Vec<IpAddr> = table
+            .shards
+            .iter()
+            .flat_map(|s| s.lock().keys().copied().collect::<Vec<_>>())
+            .collect();
+        ips.sort();
+        ips
+    }
+
+    #[test]
+    fn evicts_longest_idle_client() {
+        let table = ClientTable::with_shards(2, 1);
+        table.inc_requests(ip(1));
+        std::thread::sleep(Duration::from_millis(2));
+        table.inc_requests(ip(2));
+        std::thread::sleep(Duration::from_millis(2));
+        table.inc_requests(ip(3));
+        assert_eq!(clients(&table), [ip(2), ip(3)]);
+
+        // 活动连接中的客户端不会被淘汰，即使更久未活动
+        table.connect(ip(2));
+        std::thread::sleep(Duration::from_millis(2));
+        table.inc_requests(ip(4));
+        assert_eq!(clients(&table), [ip(2), ip(4)]);
+    }
+
+    #[test]
+    fn refuses_new_clients_when_all_active() {
+        let table = ClientTable::with_shards(2, 1);
+        table.connect(ip(1));
+        table.connect(ip(2));
+        table.connect(ip(3));
+        table.inc_requests(ip(3));
+        assert_eq!(clients(&table), [ip(1), ip(2)]);
+
+        table.disconnect(ip(1));
+        table.connect(ip(3));
+        assert_eq!(clients(&table), [ip(2), ip(3)]);
+    }
+
+    #[test]
+    fn clear_keeps_active_clients() {
+        let table = ClientTable::new(16);
+        table.connect(ip(1));
+        table.inc_requests(ip(2));
+        table.connect(ip(3));
+        table.disconnect(ip(3));
+        table.clear();
+        assert_eq!(clients(&table), [ip(1)]);
+    }
+
+    #[test]
+    fn disabled_tracks_nothing() {
+        let table = ClientTable::new(0);
+        table.connect(ip(1));
+        table.add_bytes(ip(1), 10, 20);
+        assert!(clients(&table).is_empty());
+    }
+
+    #[test]
+    fn render_counts() {
+        let table = ClientTable::new(16);
+        table.connect(ip(1));
+        table.inc_requests(ip(1));
+        table.inc_modified(ip(1));
+        table.inc_non_http(ip(1));
+        table.add_bytes(ip(1), 100, 200);
+        table.inc_requests(ip(2));
+        table.inc_requests(ip(2));
+        let rows: Vec<Vec<String>> = table
+            .render()
+            .lines()
+            .skip(1)
+            .map(|l| l.split_whitespace().skip(2).take(6).map(String::from).collect())
+            .collect();
+        assert_eq!(rows, [["0", "2", "0", "0", "0", "0"], ["1", "1", "1", "1", "100", "200"]]);
+    }
+
+    #[test]
+    fn parses_arp_table() {
+        let arp = parse_arp(
+            "IP address       HW type     Flags       HW address            Mask     Device\n\
+             192.168.1.10     0x1         0x2         aa:bb:cc:dd:ee:ff     *        br-lan\n\
+             192.168.1.11     0x1         0x0         00:00:00:00:00:00     *        br-lan\n\
+             not-an-ip        0x1         0x2         aa:bb:cc:dd:ee:00     *        br-lan\n\
+             192.168.1.12     0x1\n",
+        );
+        assert_eq!(arp, HashMap::from([(ip(10), "aa:bb:cc:dd:ee:ff".to_string())]));
+    }
+
+    #[test]
+    fn shards_keep_minimum_capacity() {
+        assert_eq!(shard_count(1), 1);
+        assert!(256 / shard_count(256) >= MIN_SHARD_CAPACITY);
+        assert!(shard_count(100_000).is_power_of_two());
+    }
+}
@@ -1,9 +1,24 @@
 use clap::{Parser, Args};
+use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
 
+use crate::firewall::KeyMode;
+use crate::logger::Filter;
+use crate::outbound::{self, Cidr, SocketOptions, Upstream};
+use crate::socks5;
+use crate::stats;
+
 // 默认值常量
 const DEFAULT_DECISION_DELAY_SECS: u64 = 60;
 const DEFAULT_HTTP_COOLDOWN_SECS: u64 = 3600;
+const DEFAULT_STATE_INTERVAL_SECS: u64 = 300;
+const DEFAULT_SCORE_WINDOW_SECS: u64 = 600;
+const DEFAULT_STATS_FILE: &str = "/tmp/uaforge.stats";
+const DEFAULT_STATS_INTERVAL_SECS: u64 = 5;
+const DEFAULT_TOP_CAPACITY: &str = "100";
+const DEFAULT_CLIENT_CAPACITY: &str = "256";
+const MAX_ERROR_BODY: usize = 1024;
+const DEFAULT_FORWARD_ALLOW: &str = "127.0.0.0/8,10.0.0.0/8,172.16.0.0/12,192.168.0.0/16,::1/128,fc00::/7";
 const DEFAULT_REGEX_PATTERN: &str = "(iPhone|iPad|Android|Macintosh|Windows|Linux|Apple|Mac OS X|Mobile)";
 
 #[derive(Clone, Debug, Args)]
@@ -17,6 +32,9 @@ pub struct FirewallConfig {
     #[arg(long, help = "Drop connections on firewall whitelist hit")]
     pub fw_drop: bool,
 
+    #[arg(long, default_value = "rst", value_parser = DropAction::parse, help = "How --fw-drop ends the connection (rst/503/redirect/pass)")]
+    pub fw_drop_action: DropAction,
+
     #[arg(long, value_delimiter = ',', help = "Firewall UA whitelist (comma-separated)")]
     pub fw_ua_w: Vec<String>,
 
@@ -26,6 +44,12 @@ pub struct FirewallConfig {
     #[arg(long, default_value = "5", help = "Non-HTTP threshold for firewall")]
     pub fw_nonhttp_threshold: u32,
 
+    #[arg(long, value_parser = parse_duration, help = "Sliding window for non-HTTP threshold (e.g., 600s, 10m)")]
+    pub fw_score_window: Option<Duration>,
+
+    #[arg(long, default_value = "0", value_parser = parse_ratio, help = "Minimum share of non-HTTP connections within the window, 0.0-1.0 (0 = disabled)")]
+    pub fw_nonhttp_ratio: f64,
+
     #[arg(long, default_value = "28800", help = "Firewall timeout in seconds")]
     pub fw_timeout: u32,
 
@@ -34,6 +58,21 @@ pub struct FirewallConfig {
 
     #[arg(long, value_parser = parse_duration, help = "Firewall HTTP cooldown (e.g., 1h, 60m)")]
     pub fw_http_cooldown: Option<Duration>,
+
+    #[arg(long, default_value = "ip-port", help = "Offload granularity (ip/ip-port/prefix/tuple)")]
+    pub fw_key: String,
+
+    #[arg(long, default_value = "24", help = "IPv4 prefix length for --fw-key prefix")]
+    pub fw_prefix_len: u8,
+
+    #[arg(long, help = "Dry-run: log offload decisions without touching ipset/nft or the state file")]
+    pub fw_dry_run: bool,
+
+    #[arg(long, help = "Firewall state file for persisting offload decisions across restarts")]
+    pub fw_state_file: Option<String>,
+
+    #[arg(long, value_parser = parse_duration, help = "Firewall state save interval (e.g., 300s, 5m)")]
+    pub fw_state_interval: Option<Duration>,
 }
 
 impl FirewallConfig {
@@ -49,6 +88,66 @@ impl FirewallConfig {
     pub fn get_http_cooldown(&self) -> Duration {
         self.fw_http_cooldown.unwrap_or_else(|| Duration::from_secs(DEFAULT_HTTP_COOLDOWN_SECS))
     }
+
+    pub fn get_score_window(&self) -> Duration {
+        self.fw_score_window
+            .filter(|d| !d.is_zero())
+            .unwrap_or_else(|| Duration::from_secs(DEFAULT_SCORE_WINDOW_SECS))
+    }
+
+    pub fn key_mode(&self) -> Result<KeyMode, String> {
+        KeyMode::parse(&self.fw_key, self.fw_prefix_len)
+    }
+
+    pub fn state_file(&self) -> Option<&str> {
+        self.fw_state_file.as_deref().filter(|s| !s.is_empty())
+    }
+
+    pub fn get_state_interval(&self) -> Duration {
+        self.fw_state_interval.unwrap_or_else(|| Duration::from_secs(DEFAULT_STATE_INTERVAL_SECS))
+    }
+}
+
+/// 防火墙白名单命中且启用 fw_drop 时如何结束当前连接，
+/// 目的是让客户端尽快重连，新连接直接走防火墙绕过
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropAction {
+    /// SO_LINGER 0 后关闭，客户端立即收到 RST
+    Rst,
+    /// 返回 503 和 Retry-After 后关闭
+    Unavailable,
+    /// 307 重定向到同一 URL 后关闭，浏览器会自动重新请求
+    Redirect,
+    /// 照常转发本次请求，响应后关闭连接
+    Pass,
+}
+
+impl DropAction {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "rst" => Ok(DropAction::Rst),
+            "503" => Ok(DropAction::Unavailable),
+            "redirect" => Ok(DropAction::Redirect),
+            "pass" => Ok(DropAction::Pass),
+            _ => Err(format!("invalid drop action: {} (expected rst/503/redirect/pass)", s)),
+        }
+    }
+}
+
+/// 令牌等敏感字符串，配置会经管理接口 /config 输出，Debug 不打印内容
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("\"***\"")
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -58,8 +157,27 @@ pub enum MatchMode {
     Regex { pattern: String },
 }
 
+/// 主监听端口的工作方式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyMode {
+    /// 透明代理：流量由防火墙 REDIRECT 进来，目标取自 SO_ORIGINAL_DST
+    Transparent,
+    /// 显式 HTTP 代理：客户端配置代理地址，目标取自绝对 URI 或 CONNECT
+    Forward,
+}
+
+impl ProxyMode {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "transparent" => Ok(ProxyMode::Transparent),
+            "forward" => Ok(ProxyMode::Forward),
+            _ => Err(format!("invalid proxy mode: {} (expected transparent/forward)", s)),
+        }
+    }
+}
+
 #[derive(Parser, Clone, Debug)]
-#[command(name = "uaforge", version = "0.1.1", about = "User-Agent modification proxy")]
+#[command(name = "uaforge", version = "0.1.1", about = "User-Agent modification proxy", disable_version_flag = true)]
 pub struct CliArgs {
     #[arg(short = 'u', long, default_value = "FFF", help = "User-Agent string to use")]
     pub user_agent: String,
@@ -67,6 +185,9 @@ pub struct CliArgs {
     #[arg(long, default_value = "8080", help = "Port to listen on")]
     pub port: u16,
 
+    #[arg(long, default_value = "transparent", value_parser = ProxyMode::parse, help = "Proxy mode (transparent/forward)")]
+    pub mode: ProxyMode,
+
     #[arg(long = "log-level", default_value = "info", help = "Log level (debug/info/warn/error)")]
     pub loglevel: String,
 
@@ -76,6 +197,12 @@ pub struct CliArgs {
     #[arg(long, help = "Log file path")]
     pub log: Option<String>,
 
+    #[arg(long, default_value = "", help = "Only log debug messages from these modules (server,handler,firewall,admin)")]
+    pub log_modules: String,
+
+    #[arg(long, default_value = "", help = "Only log debug messages for these client IPs (comma-separated)")]
+    pub log_clients: String,
+
     #[arg(short = 'w', long, value_delimiter = ',', help = "Whitelist User-Agents (comma-separated)")]
     pub whitelist: Vec<String>,
 
@@ -88,12 +215,96 @@ pub struct CliArgs {
     #[arg(long, default_value = "1000", help = "Cache size")]
     pub cache_size: usize,
 
+    #[arg(long, default_value = "0", help = "Cache shards (0 = auto, 4 per CPU core)")]
+    pub cache_shards: usize,
+
+    #[arg(long, default_value = "1M", value_parser = parse_size, help = "Cache memory budget (e.g., 512K, 2M; 0 = entry count only)")]
+    pub cache_max_bytes: usize,
+
+    #[arg(long, value_parser = parse_duration, help = "Expiry for cached modify/pass decisions (e.g., 10m; 0 = never)")]
+    pub cache_ttl: Option<Duration>,
+
+    #[arg(long, help = "Store full User-Agents in the cache to rule out hash collisions")]
+    pub cache_verify: bool,
+
     #[arg(long, help = "Force replace all User-Agents")]
     pub force: bool,
 
     #[arg(long, help = "Enable regex mode")]
     pub enable_regex: bool,
 
+    #[arg(long, default_value = DEFAULT_STATS_FILE, help = "Stats file path")]
+    pub stats_file: String,
+
+    #[arg(long, value_parser = parse_duration, help = "Stats file write interval (e.g., 5s)")]
+    pub stats_interval: Option<Duration>,
+
+    #[arg(long, default_value = "kv", value_parser = stats::Format::parse, help = "Stats file format (kv/json/off)")]
+    pub stats_format: stats::Format,
+
+    #[arg(long, default_value = DEFAULT_TOP_CAPACITY, help = "Entries tracked per top-N report category (0 = disabled)")]
+    pub top_capacity: usize,
+
+    #[arg(long, help = "Top-N report file, written with the stats file")]
+    pub top_file: Option<String>,
+
+    #[arg(long, default_value = DEFAULT_CLIENT_CAPACITY, help = "Max clients tracked for per-client stats (0 = disabled)")]
+    pub client_stats_capacity: usize,
+
+    #[arg(long, help = "Per-client stats file, written with the stats file")]
+    pub client_stats_file: Option<String>,
+
+    #[arg(long, help = "Admin API listen address, loopback only (e.g., 127.0.0.1:12033)")]
+    pub admin_listen: Option<String>,
+
+    #[arg(long, help = "Require `Authorization: Bearer <TOKEN>` on admin API requests")]
+    pub admin_token: Option<String>,
+
+    #[arg(long, default_value = DEFAULT_FORWARD_ALLOW, help = "Sources allowed to use the forward proxy (comma-separated CIDRs)")]
+    pub forward_allow: String,
+
+    #[arg(long, requires = "forward_pass", help = "Forward proxy username (enables Basic proxy auth)")]
+    pub forward_user: Option<String>,
+
+    #[arg(long, requires = "forward_user", help = "Forward proxy password")]
+    pub forward_pass: Option<String>,
+
+    #[arg(long, help = "SOCKS5 listen address, in addition to the main port (e.g., 0.0.0.0:1080)")]
+    pub socks5_listen: Option<SocketAddr>,
+
+    #[arg(long, requires = "socks5_pass", help = "SOCKS5 username (enables username/password auth)")]
+    pub socks5_user: Option<String>,
+
+    #[arg(long, requires = "socks5_user", help = "SOCKS5 password")]
+    pub socks5_pass: Option<String>,
+
+    #[arg(long, help = "Dial upstream through a proxy (socks5://[user:pass@]host:port or http://[user:pass@]host:port)")]
+    pub upstream_proxy: Option<String>,
+
+    #[arg(long, default_value = outbound::DEFAULT_DIRECT, help = "Destinations that bypass the upstream proxy (comma-separated CIDRs)")]
+    pub upstream_direct: String,
+
+    #[arg(long, value_parser = parse_mark, help = "SO_MARK for upstream sockets (e.g., 255 or 0xff)")]
+    pub upstream_mark: Option<u32>,
+
+    #[arg(long, help = "Bind upstream sockets to this interface (SO_BINDTODEVICE)")]
+    pub upstream_interface: Option<String>,
+
+    #[arg(long, help = "Source address for upstream sockets")]
+    pub upstream_source: Option<IpAddr>,
+
+    #[arg(long, help = "Expect a PROXY protocol v1/v2 header on transparent connections from trusted sources")]
+    pub proxy_protocol: bool,
+
+    #[arg(long, default_value = "127.0.0.0/8,::1/128", help = "Sources allowed to send PROXY protocol headers (comma-separated CIDRs)")]
+    pub proxy_protocol_trusted: String,
+
+    #[arg(long, value_parser = parse_error_body, help = "Body of 502/504 responses sent when the upstream fails (max 1K; default: status line)")]
+    pub error_body: Option<String>,
+
+    #[arg(long, value_parser = parse_duration, help = "Reply 504 if the upstream sends no response headers within this time (e.g., 60s; 0 = no limit)")]
+    pub upstream_timeout: Option<Duration>,
+
     #[command(flatten)]
     pub firewall: FirewallConfig,
 }
@@ -102,12 +313,42 @@ pub struct CliArgs {
 pub struct Config {
     pub user_agent: String,
     pub port: u16,
+    pub mode: ProxyMode,
     pub log_level: String,
     pub show_version: bool,
     pub log_file: Option<String>,
+    pub log_filter: Filter,
     pub whitelist: Vec<String>,
     pub cache_size: usize,
+    pub cache_shards: usize,
+    pub cache_max_bytes: usize,
+    pub cache_verify: bool,
+    pub cache_ttl: Option<Duration>,
     pub match_mode: MatchMode,
+    pub admin_listen: Option<SocketAddr>,
+    pub admin_token: Option<Secret>,
+    /// 允许使用显式代理的来源网段
+    pub forward_allow: Vec<Cidr>,
+    /// 显式代理的 Basic 认证凭据，None 表示不认证
+    pub forward_auth: Option<socks5::Auth>,
+    pub socks5_listen: Option<SocketAddr>,
+    pub socks5_auth: Option<socks5::Auth>,
+    pub upstream: Option<Upstream>,
+    pub upstream_direct: Vec<Cidr>,
+    pub upstream_socket: SocketOptions,
+    /// 启用 PROXY 协议时为可信来源网段
+    pub proxy_protocol: Option<Vec<Cidr>>,
+    /// 上游失败时代理生成的 502/504 响应体，None 时使用状态行
+    pub error_body: Option<String>,
+    /// 等待上游响应头的时限，超时返回 504；None 表示不限制
+    pub upstream_timeout: Option<Duration>,
+    pub stats_file: String,
+    pub stats_interval: Duration,
+    pub stats_format: stats::Format,
+    pub top_capacity: usize,
+    pub top_file: Option<String>,
+    pub client_stats_capacity: usize,
+    pub client_stats_file: Option<String>,
     pub firewall: FirewallConfig,
 }
 
@@ -117,24 +358,7 @@ impl Config {
         I: IntoIterator<Item = S>,
         S: Into<std::ffi::OsString> + Clone,
     {
-        // OpenWrt init script historically passes "long flags" with a single dash
-        // (e.g. `-port 12032`, `-loglevel info`, `-fw-type nft`).
-        // `clap` expects `--port` style for long flags, so normalize here for compatibility.
-        let normalized_args: Vec<std::ffi::OsString> = args
-            .into_iter()
-            .map(|s| s.into())
-            .enumerate()
-            .map(|(idx, os)| {
-                if idx == 0 {
-                    return os;
-                }
-                let s = os.to_string_lossy();
-                if s.starts_with('-') && !s.starts_with("--") && s.len() > 2 {
-                    return format!("--{}", &s[1..]).into();
-                }
-                os
-            })
-            .collect();
+        let normalized_args = normalize_args(args);
 
         if std::env::var_os("UAFORGE_DEBUG_ARGS")
             .and_then(|v| v.to_string_lossy().parse::<u8>().ok())
@@ -146,7 +370,36 @@ impl Config {
             }
         }
 
-        let cli = CliArgs::parse_from(normalized_args);
+        Self::from_cli(CliArgs::parse_from(normalized_args))
+    }
+
+    /// 与 `from_args` 相同，但解析失败时返回错误而不是退出进程（用于运行时重载）
+    pub fn try_from_args<I, S>(args: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<std::ffi::OsString> + Clone,
+    {
+        let cli = CliArgs::try_parse_from(normalize_args(args)).map_err(|e| e.to_string())?;
+        Self::from_cli(cli)
+    }
+
+    fn from_cli(cli: CliArgs) -> Result<Self, String> {
+        cli.firewall.key_mode()?;
+        let admin_listen = cli.admin_listen.as_deref().map(parse_admin_listen).transpose()?;
+        let log_filter = Filter::parse(&cli.log_modules, &cli.log_clients)?;
+        let upstream = cli
+            .upstream_proxy
+            .as_deref()
+            .filter(|s| !s.is_empty())
+            .map(Upstream::parse)
+            .transpose()?;
+        let upstream_direct = Cidr::parse_list(&cli.upstream_direct)?;
+        let forward_allow = Cidr::parse_list(&cli.forward_allow)?;
+        let proxy_protocol = if cli.proxy_protocol {
+            Some(Cidr::parse_list(&cli.proxy_protocol_trusted)?)
+        } else {
+            None
+        };
 
         // Determine match mode
         let match_mode = if cli.force {
@@ -168,17 +421,134 @@ impl Config {
         Ok(Self {
             user_agent: cli.user_agent,
             port: cli.port,
+            mode: cli.mode,
             log_level: cli.loglevel,
             show_version: cli.version,
             log_file: cli.log,
+            log_filter,
             whitelist: cli.whitelist,
             cache_size: cli.cache_size,
+            cache_shards: cli.cache_shards,
+            cache_max_bytes: cli.cache_max_bytes,
+            cache_verify: cli.cache_verify,
+            cache_ttl: cli.cache_ttl.filter(|d| !d.is_zero()),
             match_mode,
+            admin_listen,
+            admin_token: cli.admin_token.filter(|s| !s.is_empty()).map(Secret),
+            forward_allow,
+            forward_auth: cli
+                .forward_user
+                .zip(cli.forward_pass)
+                .filter(|(user, _)| !user.is_empty())
+                .map(|(user, pass)| socks5::Auth { user, pass }),
+            socks5_listen: cli.socks5_listen,
+            socks5_auth: cli
+                .socks5_user
+                .zip(cli.socks5_pass)
+                .filter(|(user, _)| !user.is_empty())
+                .map(|(user, pass)| socks5::Auth { user, pass }),
+            upstream,
+            upstream_direct,
+            upstream_socket: SocketOptions {
+                mark: cli.upstream_mark.filter(|m| *m != 0),
+                interface: cli.upstream_interface.filter(|s| !s.is_empty()),
+                source: cli.upstream_source,
+            },
+            proxy_protocol,
+            error_body: cli.error_body.filter(|s| !s.is_empty()),
+            upstream_timeout: cli.upstream_timeout.filter(|d| !d.is_zero()),
+            stats_file: cli.stats_file,
+            stats_interval: cli
+                .stats_interval
+                .filter(|d| !d.is_zero())
+                .unwrap_or_else(|| Duration::from_secs(DEFAULT_STATS_INTERVAL_SECS)),
+            stats_format: cli.stats_format,
+            top_capacity: cli.top_capacity,
+            top_file: cli.top_file.filter(|s| !s.is_empty()),
+            client_stats_capacity: cli.client_stats_capacity,
+            client_stats_file: cli.client_stats_file.filter(|s| !s.is_empty()),
             firewall: cli.firewall,
         })
     }
 }
 
+fn normalize_args<I, S>(args: I) -> Vec<std::ffi::OsString>
+where
+    I: IntoIterator<Item = S>,
+    S: Into<std::ffi::OsString> + Clone,
+{
+    // OpenWrt init script historically passes "long flags" with a single dash
+    // (e.g. `-port 12032`, `-loglevel info`, `-fw-type nft`).
+    // `clap` expects `--port` style for long flags, so normalize here for compatibility.
+    args
+        .into_iter()
+        .map(|s| s.into())
+        .enumerate()
+        .map(|(idx, os)| {
+            if idx == 0 {
+                return os;
+            }
+            let s = os.to_string_lossy();
+            if s.starts_with('-') && !s.starts_with("--") && s.len() > 2 {
+                return format!("--{}", &s[1..]).into();
+            }
+            os
+        })
+        .collect()
+}
+
+/// 管理接口只允许监听回环地址：令牌是可选的，未配置时本机任何进程都能调用
+fn parse_admin_listen(s: &str) -> Result<SocketAddr, String> {
+    let addr: SocketAddr = s
+        .parse()
+        .map_err(|_| format!("invalid admin listen address: {} (expected ip:port)", s))?;
+    if !addr.ip().is_loopback() {
+        return Err(format!("admin listen address must be loopback: {}", s));
+    }
+    Ok(addr)
+}
+
+/// 十进制或 0x 开头的十六进制
+fn parse_mark(s: &str) -> Result<u32, String> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse::<u32>(),
+    }
+    .map_err(|_| format!("invalid mark: {} (expected e.g. 255 or 0xff)", s))
+}
+
+/// 错误响应体只是一句提示，限制长度
+fn parse_error_body(s: &str) -> Result<String, String> {
+    if s.len() > MAX_ERROR_BODY {
+        return Err(format!("error body too long: {} bytes (max {})", s.len(), MAX_ERROR_BODY));
+    }
+    Ok(s.to_string())
+}
+
+/// 0.0 到 1.0 之间的比例
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(r) if (0.0..=1.0).contains(&r) => Ok(r),
+        _ => Err(format!("invalid ratio: {} (expected 0.0-1.0)", s)),
+    }
+}
+
+/// 字节数，可带 K/M 后缀（1024 进制）
+fn parse_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let (num_str, multiplier) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1024),
+        Some((i, 'm' | 'M')) => (&s[..i], 1024 * 1024),
+        _ => (s, 1),
+    };
+    num_str
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size: {} (expected format: 65536, 512K, 2M)", s))
+}
+
 fn parse_duration(s: &str) -> Result<Duration, String> {
     let s = s.trim();
     if s.is_empty() {
@@ -206,3 +576,18 @@ fn parse_duration(s: &str) -> Result<Duration, String> {
         _ => Err(format!("invalid duration unit: {} (expected s/m/h)", unit)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ratio_bounds() {
+        assert_eq!(parse_ratio("0"), Ok(0.0));
+        assert_eq!(parse_ratio("0.5"), Ok(0.5));
+        assert_eq!(parse_ratio("1"), Ok(1.0));
+        for bad in ["-0.1", "1.5", "NaN", "inf", "abc", ""] {
+            assert!(parse_ratio(bad).is_err(), "{bad}");
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use super::key::{KeyMode, OffloadKey};
+use crate::config::FirewallConfig;
+
+/// 防火墙集合后端：决策引擎只通过此接口读写内核集合
+pub trait Backend: Send {
+    /// 后端名称，用于日志
+    fn name(&self) -> &str;
+
+    /// 批量添加 (条目, 超时秒)，超时为 0 表示使用集合默认值
+    fn add(&mut self, items: &[(OffloadKey, u32)]) -> io::Result<()>;
+
+    /// 批量删除
+    fn remove(&mut self, items: &[OffloadKey]) -> io::Result<()>;
+
+    /// 列出集合中的现有条目
+    fn list(&mut self) -> io::Result<Vec<OffloadKey>>;
+}
+
+/// 根据配置选择后端；演练模式使用内存后端，保证不触碰内核集合
+pub fn from_config(fw_config: &FirewallConfig, mode: KeyMode) -> Option<Box<dyn Backend>> {
+    let fw_type = fw_config.fw_type.as_deref().unwrap_or("");
+    let set_name = fw_config.fw_set_name.as_deref().unwrap_or("");
+    if fw_type.is_empty() || set_name.is_empty() {
+        return None;
+    }
+
+    if fw_config.fw_dry_run {
+        return Some(Box::new(MemoryBackend::new()));
+    }
+
+    Some(match fw_type {
+        "nft" => Box::new(NftBackend::new(set_name, mode)),
+        _ => Box::new(IpsetBackend::new(set_name, mode)),
+    })
+}
+
+/// nftables (fw4) 集合
+pub struct NftBackend {
+    set_name: String,
+    mode: KeyMode,
+}
+
+impl NftBackend {
+    pub fn new(set_name: &str, mode: KeyMode) -> Self {
+        Self { set_name: set_name.to_string(), mode }
+    }
+
+    fn element_cmd(&self, op: &str, elements: &str) -> io::Result<()> {
+        // nft <op> element inet fw4 <setName> { <element> timeout <t>, ... }
+        let out = Command::new("nft")
+            .args([op, "element", "inet", "fw4", &self.set_name, "{", elements, "}"])
+            .output()?;
+        if !out.status.success() {
+            return Err(io::Error::other(format!(
+                "nft failed: {}",
+                String::from_utf8_lossy(&out.stderr)
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Backend for NftBackend {
+    fn name(&self) -> &str {
+        "nft"
+    }
+
+    fn add(&mut self, items: &[(OffloadKey, u32)]) -> io::Result<()> {
+        let mut elements = String::new();
+        for (idx, (key, timeout)) in items.iter().enumerate() {
+            if idx > 0 {
+                elements.push_str(", ");
+            }
+            elements.push_str(&key.nft_element());
+            if *timeout > 0 {
+                elements.push_str(&format!(" timeout {timeout}s"));
+            }
+        }
+        self.element_cmd("add", &elements)
+    }
+
+    fn remove(&mut self, items: &[OffloadKey]) -> io::Result<()> {
+        let elements = items
+            .iter()
+            .map(OffloadKey::nft_element)
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.element_cmd("delete", &elements)
+    }
+
+    fn list(&mut self) -> io::Result<Vec<OffloadKey>> {
+        let out = Command::new("nft")
+            .args(["list", "set", "inet", "fw4", &self.set_name])
+            .output()?;
+        if !out.status.success() {
+            return Err(io::Error::other(format!(
+                "nft list failed: {}",
+                String::from_utf8_lossy(&out.stderr)
+            )));
+        }
+        Ok(parse_nft_elements(self.mode, &String::from_utf8_lossy(&out.stdout)))
+    }
+}
+
+/// 解析 `nft list set` 输出中的 `elements = { 1.2.3.4 . 443 timeout 8h expires 7h, ... }`
+fn parse_nft_elements(mode: KeyMode, output: &str) -> Vec<OffloadKey> {
+    let Some(start) = output.find("elements = {") else {
+        return Vec::new();
+    };
+    let body = &output[start + "elements = {".len()..];
+    let body = body.split('}').next().unwrap_or("");
+
+    body.split(',')
+        .filter_map(|elem| {
+            let elem = elem.split(" timeout ").next()?.split(" expires ").next()?;
+            OffloadKey::parse_nft(mode, elem.trim())
+        })
+        .collect()
+}
+
+/// iptables 使用的 ipset，类型随卸载粒度变化（hash:ip / hash:ip,port / hash:net / hash:ip,port,ip）
+pub struct IpsetBackend {
+    set_name: String,
+    mode: KeyMode,
+}
+
+impl IpsetBackend {
+    pub fn new(set_name: &str, mode: KeyMode) -> Self {
+        Self { set_name: set_name.to_string(), mode }
+    }
+
+    fn restore(&self, script: &str) -> io::Result<()> {
+        let mut child = Command::new("ipset")
+            .arg("restore")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(mut s) = child.stdin.take() {
+            let _ = s.write_all(script.as_bytes());
+        }
+        let out = child.wait_with_output()?;
+        if !out.status.success() {
+            return Err(io::Error::other(format!(
+                "ipset restore failed: {}",
+                String::from_utf8_lossy(&out.stderr)
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Backend for IpsetBackend {
+    fn name(&self) -> &str {
+        "ipset"
+    }
+
+    fn add(&mut self, items: &[(OffloadKey, u32)]) -> io::Result<()> {
+        let set_name = &self.set_name;
+        let mut script = String::new();
+        for (key, timeout) in items {
+            let elem = key.ipset_element();
+            if *timeout > 0 {
+                script.push_str(&format!("add {set_name} {elem} timeout {timeout} -exist\n"));
+            } else {
+                script.push_str(&format!("add {set_name} {elem} -exist\n"));
+            }
+        }
+        self.restore(&script)
+    }
+
+    fn remove(&mut self, items: &[OffloadKey]) -> io::Result<()> {
+        let set_name = &self.set_name;
+        let mut script = String::new();
+        for key in items {
+            script.push_str(&format!("del {set_name} {} -exist\n", key.ipset_element()));
+        }
+        self.restore(&script)
+    }
+
+    fn list(&mut self) -> io::Result<Vec<OffloadKey>> {
+        let out = Command::new("ipset").args(["list", &self.set_name]).output()?;
+        if !out.status.success() {
+            return Err(io::Error::other(format!(
+                "ipset list failed: {}",
+                String::from_utf8_lossy(&out.stderr)
+            )));
+        }
+        Ok(parse_ipset_members(self.mode, &String::from_utf8_lossy(&out.stdout)))
+    }
+}
+
+/// 解析 `ipset list` 输出 `Members:` 之后的 `1.2.3.4,tcp:443 timeout 100`
+fn parse_ipset_members(mode: KeyMode, output: &str) -> Vec<OffloadKey> {
+    output
+        .lines()
+        .skip_while(|l| !l.starts_with("Members:"))
+        .skip(1)
+        .filter_map(|line| OffloadKey::parse_ipset(mode, line.split_whitespace().next()?))
+        .collect()
+}
+
+/// 后端操作记录
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Add(Vec<(OffloadKey, u32)>),
+    Remove(Vec<OffloadKey>),
+}
+
+/// 内存后端：只记录条目与操作，不执行任何外部命令。
+/// 用于演练模式，以及在无 root 环境下驱动决策引擎。
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    entries: Arc<Mutex<HashMap<OffloadKey, u32>>>,
+    ops: Arc<Mutex<Vec<Op>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 已执行的操作序列（克隆出的句柄共享同一份记录）
+    #[cfg(test)]
+    pub fn ops(&self) -> Vec<Op> {
+        self.ops.lock().clone()
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    fn add(&mut self, items: &[(OffloadKey, u32)]) -> io::Result<()> {
+        let mut entries = self.entries.lock();
+        for (key, timeout) in items {
+            entries.insert(*key, *timeout);
+        }
+        self.ops.lock().push(Op::Add(items.to_vec()));
+        Ok(())
+    }
+
+    fn remove(&mut self, items: &[OffloadKey]) -> io::Result<()> {
+        let mut entries = self.entries.lock();
+        for key in items {
+            entries.remove(key);
+        }
+        self.ops.lock().push(Op::Remove(items.to_vec()));
+        Ok(())
+    }
+
+    fn list(&mut self) -> io::Result<Vec<OffloadKey>> {
+        Ok(self.entries.lock().keys().copied().collect())
+    }
+}
@@ -0,0 +1,52 @@
+use std::time::Instant;
+
+/// 决策引擎的时间来源，可替换为手动时钟以确定性地驱动状态机
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+}
+
+/// 系统单调时钟
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(test)]
+pub use manual::ManualClock;
+
+#[cfg(test)]
+mod manual {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use parking_lot::Mutex;
+
+    use super::Clock;
+
+    /// 手动推进的时钟，克隆出的句柄共享同一时间
+    #[derive(Clone)]
+    pub struct ManualClock {
+        now: Arc<Mutex<Instant>>,
+    }
+
+    impl ManualClock {
+        pub fn new() -> Self {
+            Self {
+                now: Arc::new(Mutex::new(Instant::now())),
+            }
+        }
+
+        pub fn advance(&self, d: Duration) {
+            *self.now.lock() += d;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.now.lock()
+        }
+    }
+}
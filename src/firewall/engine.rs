@@ -0,0 +1,714 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::backend::Backend;
+use super::clock::Clock;
+use super::key::{KeyMode, OffloadKey};
+use super::score::ScoreWindow;
+use super::state;
+use super::{Event, Offloaded};
+use crate::config::FirewallConfig;
+use crate::logger;
+use crate::stats::Stats;
+
+// 常量定义
+const CLEANUP_INTERVAL_SECS: u64 = 10 * 60; // 10 分钟
+const BATCH_FLUSH_DELAY_MS: u64 = 100;
+const BATCH_SIZE_THRESHOLD: usize = 200;
+
+/// 卸载原因，用于演练模式的审计日志
+#[derive(Debug, Clone, Copy)]
+enum OffloadReason {
+    UaWhitelist,
+    NonHttp { non_http: u32, http: u32 },
+}
+
+/// 某个卸载条目（按当前粒度聚合）的计分画像
+#[derive(Debug)]
+pub(super) struct PortProfile {
+    pub window: ScoreWindow,
+    pub http_lock_expires: Option<Instant>,
+    pub last_event: Instant,
+    pub decision_deadline: Option<Instant>,
+}
+
+impl PortProfile {
+    fn new(now: Instant) -> Self {
+        Self {
+            window: ScoreWindow::new(now),
+            http_lock_expires: None,
+            last_event: now,
+            decision_deadline: None,
+        }
+    }
+}
+
+/// 卸载决策状态机。
+///
+/// 不持有线程和通道：由 worker 投递事件并按 `next_timeout` 调用 `tick`，
+/// 时间全部来自注入的 `Clock`，集合操作全部经由 `Backend`。
+pub(super) struct Engine {
+    config: FirewallConfig,
+    mode: KeyMode,
+    clock: Box<dyn Clock>,
+    backend: Option<Box<dyn Backend>>,
+    stats: Arc<Stats>,
+
+    profiles: HashMap<OffloadKey, PortProfile>,
+    // 已写入集合的条目及其过期时间（None 表示永久），用于状态持久化；
+    // 与 FirewallManager 共享，代理据此判断断开连接是否安全
+    offloaded: Offloaded,
+
+    // Batch state: dedup by key; single set/type pair in current OpenWrt usage.
+    batch: HashMap<OffloadKey, u32>,
+    batch_deadline: Option<Instant>,
+
+    cleanup_interval: Duration,
+    cleanup_deadline: Instant,
+
+    state_file: Option<String>,
+    state_interval: Duration,
+    state_deadline: Option<Instant>,
+}
+
+impl Engine {
+    pub fn new(
+        config: FirewallConfig,
+        mode: KeyMode,
+        backend: Option<Box<dyn Backend>>,
+        clock: Box<dyn Clock>,
+        stats: Arc<Stats>,
+        offloaded: Offloaded,
+    ) -> Self {
+        let now = clock.now();
+        let cleanup_interval = Duration::from_secs(CLEANUP_INTERVAL_SECS);
+        let state_file = config.state_file().map(str::to_string);
+        let state_interval = config.get_state_interval();
+        let state_deadline = state_file.as_ref().map(|_| now + state_interval);
+
+        Self {
+            config,
+            mode,
+            clock,
+            backend,
+            stats,
+            profiles: HashMap::new(),
+            offloaded,
+            batch: HashMap::new(),
+            batch_deadline: None,
+            cleanup_interval,
+            cleanup_deadline: now + cleanup_interval,
+            state_file,
+            state_interval,
+            state_deadline,
+        }
+    }
+
+    /// 距离下一个定时任务的时间
+    pub fn next_timeout(&self) -> Option<Duration> {
+        let next = [
+            self.batch_deadline,
+            Some(self.cleanup_deadline),
+            self.decision_deadline(),
+            self.state_deadline,
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let now = self.clock.now();
+        next.map(|t| t.saturating_duration_since(now))
+    }
+
+    pub fn handle(&mut self, evt: Event) {
+        let now = self.clock.now();
+        match evt {
+            Event::Stop => {}
+            Event::List { reply } => {
+                let _ = reply.send(self.list());
+            }
+            Event::Remove { keys, reply } => {
+                let _ = reply.send(self.remove(keys));
+            }
+            Event::Add { src, ip, port, timeout } => {
+                let key = self.mode.key(src, ip, port);
+                self.offload(key, timeout, OffloadReason::UaWhitelist);
+            }
+            Event::Http { src, ip, port } => {
+                let key = self.mode.key(src, ip, port);
+                if self.simulated(key, now) {
+                    return;
+                }
+                let window = self.config.get_score_window();
+                let p = self
+                    .profiles
+                    .entry(key)
+                    .or_insert_with(|| PortProfile::new(now));
+
+                // HTTP 连接始终计入窗口，用于非 HTTP / HTTP 比例判定
+                p.window.record_http(now, window);
+
+                // Within cooldown, ignore.
+                if p.http_lock_expires.is_some_and(|t| now < t) {
+                    return;
+                }
+
+                p.http_lock_expires = Some(now + self.config.get_http_cooldown());
+                p.decision_deadline = None;
+                p.last_event = now;
+            }
+            Event::NonHttp { src, ip, port } => {
+                let key = self.mode.key(src, ip, port);
+                if self.simulated(key, now) {
+                    return;
+                }
+                let window = self.config.get_score_window();
+                let decision_delay = self.config.get_decision_delay();
+                let p = self
+                    .profiles
+                    .entry(key)
+                    .or_insert_with(|| PortProfile::new(now));
+
+                p.window.record_non_http(now, window);
+                p.last_event = now;
+
+                // Ignore during HTTP cooldown.
+                if p.http_lock_expires.is_some_and(|t| now < t) {
+                    return;
+                }
+
+                if p.decision_deadline.is_none() && qualifies(&self.config, p, now) {
+                    p.decision_deadline = Some(now + decision_delay);
+                    logger::log_at(
+                        logger::Level::Debug,
+                        "firewall",
+                        Some(src),
+                        format_args!("offload decision for {key} scheduled in {}s", decision_delay.as_secs()),
+                    );
+                }
+            }
+        }
+    }
+
+    /// 处理到期的定时任务：批量写入、决策、清理、状态快照
+    pub fn tick(&mut self) {
+        // Timers: batch flush
+        if self.batch_deadline.is_some_and(|t| self.clock.now() >= t) && !self.batch.is_empty() {
+            self.flush_batch();
+        }
+
+        // Timers: finalize decisions
+        for (k, (non_http, http)) in self.finalize_decisions() {
+            let reason = OffloadReason::NonHttp { non_http, http };
+            self.offload(k, self.config.fw_timeout, reason);
+        }
+
+        // Timers: cleanup
+        if self.clock.now() >= self.cleanup_deadline {
+            self.cleanup_profiles();
+            self.cleanup_offloaded();
+            self.cleanup_deadline = self.clock.now() + self.cleanup_interval;
+        }
+
+        // Timers: state snapshot
+        if self.state_deadline.is_some_and(|t| self.clock.now() >= t) {
+            self.save_state();
+            self.state_deadline = Some(self.clock.now() + self.state_interval);
+        }
+    }
+
+    /// 已卸载条目及剩余超时秒（0 表示永久），按条目排序
+    fn list(&self) -> Vec<(OffloadKey, u64)> {
+        let now = self.clock.now();
+        let mut entries: Vec<(OffloadKey, u64)> = self
+            .offloaded
+            .lock()
+            .iter()
+            .filter_map(|(k, expires)| match expires {
+                Some(t) if *t <= now => None,
+                Some(t) => Some((*k, t.saturating_duration_since(now).as_secs().max(1))),
+                None => Some((*k, 0)),
+            })
+            .collect();
+        entries.sort_by_cached_key(|(k, _)| k.to_string());
+        entries
+    }
+
+    /// 手动撤销卸载：从集合、待写批次和计分画像中一并删除，返回删除的条目数。
+    /// 粒度不符的条目不可能存在于当前集合，直接忽略。
+    fn remove(&mut self, keys: Vec<OffloadKey>) -> std::io::Result<usize> {
+        let mode = self.mode;
+        let keys: Vec<OffloadKey> = keys.into_iter().filter(|k| mode.matches(k)).collect();
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        if let Some(backend) = self.backend.as_mut() {
+            backend.remove(&keys)?;
+        }
+        for key in &keys {
+            self.offloaded.lock().remove(key);
+            self.batch.remove(key);
+            self.profiles.remove(key);
+        }
+        logger::log(
+            logger::Level::Info,
+            format_args!("removed {} firewall offload entries", keys.len()),
+        );
+        Ok(keys.len())
+    }
+
+    /// 退出前写出剩余批次和状态文件
+    pub fn finish(&mut self) {
+        if !self.batch.is_empty() {
+            self.flush_batch();
+        }
+        self.save_state();
+    }
+
+    /// 启动时恢复：已卸载条目重新写入集合（防火墙重载会清空集合），画像放回内存
+    pub fn restore(&mut self) {
+        let Some(path) = self.state_file.clone() else {
+            return;
+        };
+        let now = self.clock.now();
+        let snapshot = match state::load(&path, now, self.config.get_score_window()) {
+            Ok(s) => s,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                logger::log(
+                    logger::Level::Warn,
+                    format_args!("failed to load firewall state {}: {}", path, e),
+                );
+                return;
+            }
+        };
+
+        // 集合仍在（仅重启了进程）的条目无需重新写入
+        let existing: HashSet<OffloadKey> = self
+            .backend
+            .as_mut()
+            .and_then(|b| b.list().ok())
+            .map(HashSet::from_iter)
+            .unwrap_or_default();
+
+        // 粒度切换后旧条目与新集合类型不符，直接丢弃
+        let mode = self.mode;
+        let offloaded: Vec<(OffloadKey, u32)> = snapshot
+            .offloaded
+            .into_iter()
+            .filter(|(k, _)| mode.matches(k))
+            .collect();
+        let profiles: Vec<(OffloadKey, PortProfile)> = snapshot
+            .profiles
+            .into_iter()
+            .filter(|(k, _)| mode.matches(k))
+            .collect();
+        let offload_count = offloaded.len();
+        let profile_count = profiles.len();
+
+        for (key, timeout) in offloaded {
+            if existing.contains(&key) {
+                let expires = (timeout > 0).then(|| now + Duration::from_secs(timeout as u64));
+                self.offloaded.lock().insert(key, expires);
+            } else {
+                self.batch.insert(key, timeout);
+            }
+        }
+        if !self.batch.is_empty() {
+            self.batch_deadline = Some(now);
+        }
+        self.profiles.extend(profiles);
+
+        logger::log(
+            logger::Level::Info,
+            format_args!(
+                "restored firewall state from {}: {} offload entries, {} profiles",
+                path, offload_count, profile_count
+            ),
+        );
+    }
+
+    fn save_state(&self) {
+        let Some(path) = self.state_file.as_deref() else {
+            return;
+        };
+        // 演练模式的条目只存在于内存后端，不能持久化，否则关闭演练后会被写入内核；
+        // 也不能写出空集合，否则会覆盖上次正式运行保存的卸载条目，因此整份快照都不写
+        if self.config.fw_dry_run {
+            return;
+        }
+        let window = self.config.get_score_window();
+        if let Err(e) = state::save(path, self.clock.now(), window, &self.offloaded.lock(), &self.profiles) {
+            logger::log(
+                logger::Level::Warn,
+                format_args!("failed to save firewall state {}: {}", path, e),
+            );
+        }
+    }
+
+    fn decision_deadline(&self) -> Option<Instant> {
+        self.profiles
+            .values()
+            .filter_map(|p| p.decision_deadline)
+            .min()
+    }
+
+    /// 取出已到期且满足条件的决策，返回 (条目, 窗口内 (非 HTTP, HTTP) 次数)。
+    /// 到期时已不满足条件（计数衰减或进入 HTTP 冷却）的决策直接取消。
+    fn finalize_decisions(&mut self) -> Vec<(OffloadKey, (u32, u32))> {
+        let now = self.clock.now();
+        let window = self.config.get_score_window();
+        let mut keys = Vec::new();
+
+        for (k, p) in self.profiles.iter_mut() {
+            if p.decision_deadline.is_none_or(|t| now < t) {
+                continue;
+            }
+            if qualifies(&self.config, p, now) && p.http_lock_expires.is_none_or(|t| now >= t) {
+                keys.push(*k);
+            } else {
+                p.decision_deadline = None;
+            }
+        }
+
+        keys.into_iter()
+            .filter_map(|k| self.profiles.remove(&k).map(|p| (k, p.window.counts(now, window))))
+            .collect()
+    }
+
+    /// 加入待写批次；演练模式下同时记录审计日志和统计
+    fn offload(&mut self, key: OffloadKey, timeout: u32, reason: OffloadReason) {
+        let now = self.clock.now();
+        if self.config.fw_dry_run {
+            if self.simulated(key, now) {
+                return;
+            }
+            self.audit(key, timeout, reason);
+        }
+        if let OffloadReason::NonHttp { .. } = reason {
+            self.stats.top().record_offload(key);
+        }
+
+        self.batch.insert(key, timeout);
+        if self.batch_deadline.is_none() {
+            self.batch_deadline = Some(now + Duration::from_millis(BATCH_FLUSH_DELAY_MS));
+        }
+        if self.batch.len() >= BATCH_SIZE_THRESHOLD {
+            self.flush_batch();
+        }
+    }
+
+    /// 演练模式：记录本应卸载的条目及原因
+    fn audit(&self, key: OffloadKey, timeout: u32, reason: OffloadReason) {
+        match reason {
+            OffloadReason::UaWhitelist => {
+                self.stats.inc_fw_dry_run_ua_whitelist();
+                logger::log(
+                    logger::Level::Info,
+                    format_args!("[dry-run] would offload {key} for {timeout}s (reason: ua-whitelist)"),
+                );
+            }
+            OffloadReason::NonHttp { non_http, http } => {
+                self.stats.inc_fw_dry_run_nonhttp();
+                logger::log(
+                    logger::Level::Info,
+                    format_args!(
+                        "[dry-run] would offload {key} for {timeout}s (reason: non-http, non-http: {non_http}, http: {http})"
+                    ),
+                );
+            }
+        }
+    }
+
+    /// 演练模式下视为已卸载：真实卸载后流量不会再进入代理，这里据此忽略后续事件
+    fn simulated(&self, key: OffloadKey, now: Instant) -> bool {
+        self.config.fw_dry_run
+            && self
+                .offloaded
+                .lock()
+                .get(&key)
+                .is_some_and(|expires| expires.is_none_or(|t| now < t))
+    }
+
+    fn cleanup_profiles(&mut self) {
+        let now = self.clock.now();
+        let interval = self.cleanup_interval;
+        self.profiles.retain(|_, p| {
+            // Keep if in decision window or cooldown.
+            if p.decision_deadline.is_some() {
+                return true;
+            }
+            if p.http_lock_expires.is_some_and(|t| now < t) {
+                return true;
+            }
+            now.saturating_duration_since(p.last_event) <= interval
+        });
+    }
+
+    fn cleanup_offloaded(&mut self) {
+        let now = self.clock.now();
+        self.offloaded
+            .lock()
+            .retain(|_, expires| expires.is_none_or(|t| now < t));
+    }
+
+    fn flush_batch(&mut self) {
+        self.batch_deadline = None;
+        let Some(backend) = self.backend.as_mut() else {
+            self.batch.clear();
+            return;
+        };
+
+        let items: Vec<(OffloadKey, u32)> = self.batch.drain().collect();
+
+        if items.is_empty() {
+            return;
+        }
+
+        match backend.add(&items) {
+            Ok(()) => {
+                let now = self.clock.now();
+                // 演练模式的条目只写入内存后端，不计为真实卸载
+                if !self.config.fw_dry_run {
+                    self.stats.add_fw_offload_added(items.len());
+                }
+                let mut offloaded = self.offloaded.lock();
+                for (key, timeout) in items {
+                    let expires = (timeout > 0).then(|| now + Duration::from_secs(timeout as u64));
+                    offloaded.insert(key, expires);
+                }
+            }
+            Err(e) => {
+                self.stats.inc_fw_batch_failures();
+                let set_name = self.config.fw_set_name.as_deref().unwrap_or("");
+                logger::log(
+                    logger::Level::Warn,
+                    format_args!("firewall batch failed ({}/{}): {}", backend.name(), set_name, e),
+                );
+            }
+        }
+    }
+}
+
+/// 窗口内非 HTTP 次数达到阈值，且非 HTTP 占全部连接的比例不低于下限
+fn qualifies(config: &FirewallConfig, p: &PortProfile, now: Instant) -> bool {
+    let (non_http, http) = p.window.counts(now, config.get_score_window());
+    non_http >= config.fw_nonhttp_threshold
+        && non_http as f64 >= config.fw_nonhttp_ratio * (non_http as f64 + http as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::firewall::backend::{MemoryBackend, Op};
+    use crate::firewall::clock::ManualClock;
+
+    const SRC: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+    const DST: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1));
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        fw: FirewallConfig,
+    }
+
+    struct Harness {
+        engine: Engine,
+        clock: ManualClock,
+        backend: MemoryBackend,
+    }
+
+    impl Harness {
+        fn new(args: &[&str]) -> Self {
+            let argv = ["uaforge", "--fw-type", "nft", "--fw-set-name", "test"];
+            let config = Cli::parse_from(argv.iter().chain(args)).fw;
+            let mode = config.key_mode().unwrap();
+            let clock = ManualClock::new();
+            let backend = MemoryBackend::new();
+            let engine = Engine::new(
+                config,
+                mode,
+                Some(Box::new(backend.clone())),
+                Box::new(clock.clone()),
+                Arc::new(Stats::new(0, 0)),
+                Default::default(),
+            );
+            Self { engine, clock, backend }
+        }
+
+        fn key(&self, port: u16) -> OffloadKey {
+            self.engine.mode.key(SRC, DST, port)
+        }
+
+        fn http(&mut self) {
+            self.engine.handle(Event::Http { src: SRC, ip: DST, port: 443 });
+        }
+
+        fn non_http(&mut self, n: usize) {
+            for _ in 0..n {
+                self.engine.handle(Event::NonHttp { src: SRC, ip: DST, port: 443 });
+            }
+        }
+
+        fn advance(&mut self, d: Duration) {
+            self.clock.advance(d);
+            self.engine.tick();
+        }
+
+        fn scheduled(&self) -> bool {
+            self.engine
+                .profiles
+                .get(&self.key(443))
+                .is_some_and(|p| p.decision_deadline.is_some())
+        }
+
+        /// 后端累计写入的条目
+        fn added(&self) -> Vec<(OffloadKey, u32)> {
+            self.backend
+                .ops()
+                .into_iter()
+                .flat_map(|op| match op {
+                    Op::Add(items) => items,
+                    Op::Remove(_) => Vec::new(),
+                })
+                .collect()
+        }
+    }
+
+    const FLUSH: Duration = Duration::from_millis(BATCH_FLUSH_DELAY_MS);
+
+    #[test]
+    fn threshold_crossing_offloads_after_delay() {
+        let mut h = Harness::new(&["--fw-decision-delay", "10s"]);
+        h.non_http(4);
+        assert!(!h.scheduled());
+        h.non_http(1);
+        assert!(h.scheduled());
+
+        h.advance(Duration::from_secs(10));
+        assert!(h.added().is_empty(), "written before the batch delay");
+        assert!(h.engine.batch.contains_key(&h.key(443)));
+
+        h.advance(FLUSH);
+        assert_eq!(h.added(), vec![(h.key(443), 28800)]);
+        assert_eq!(h.engine.list(), vec![(h.key(443), 28800)]);
+    }
+
+    #[test]
+    fn decision_waits_for_delay() {
+        let mut h = Harness::new(&["--fw-decision-delay", "10s"]);
+        h.non_http(5);
+        h.advance(Duration::from_secs(9));
+        assert!(h.scheduled());
+        assert!(h.engine.batch.is_empty());
+        h.advance(Duration::from_secs(1));
+        assert!(!h.engine.batch.is_empty());
+    }
+
+    #[test]
+    fn http_during_delay_cancels_decision() {
+        let mut h = Harness::new(&["--fw-decision-delay", "10s"]);
+        h.non_http(5);
+        h.advance(Duration::from_secs(5));
+        h.http();
+        assert!(!h.scheduled());
+
+        h.advance(Duration::from_secs(5));
+        h.advance(FLUSH);
+        assert!(h.added().is_empty());
+    }
+
+    #[test]
+    fn http_cooldown_suppresses_scheduling() {
+        let mut h = Harness::new(&["--fw-http-cooldown", "60s"]);
+        h.http();
+        h.non_http(10);
+        assert!(!h.scheduled());
+
+        // 冷却结束后，窗口内已有的非 HTTP 计数仍然有效
+        h.advance(Duration::from_secs(60));
+        h.non_http(1);
+        assert!(h.scheduled());
+    }
+
+    #[test]
+    fn ratio_blocks_mixed_traffic() {
+        let mut h = Harness::new(&["--fw-http-cooldown", "1s", "--fw-nonhttp-ratio", "0.75"]);
+        for _ in 0..3 {
+            h.http();
+            h.advance(Duration::from_secs(1));
+        }
+        h.non_http(8);
+        assert!(!h.scheduled(), "8 of 11 connections is below ratio 0.75");
+        h.non_http(1);
+        assert!(h.scheduled());
+    }
+
+    #[test]
+    fn dry_run_records_once_and_ignores_followups() {
+        let mut h = Harness::new(&["--fw-dry-run", "--fw-decision-delay", "10s"]);
+        h.non_http(5);
+        h.advance(Duration::from_secs(10));
+        h.advance(FLUSH);
+        assert_eq!(h.added(), vec![(h.key(443), 28800)]);
+
+        // 视为已卸载：后续事件既不再计分，也不会重复写入
+        h.non_http(5);
+        assert!(!h.engine.profiles.contains_key(&h.key(443)));
+        h.engine.handle(Event::Add { src: SRC, ip: DST, port: 443, timeout: 60 });
+        assert!(h.engine.batch.is_empty());
+        h.advance(FLUSH);
+        assert_eq!(h.backend.ops().len(), 1);
+    }
+
+    #[test]
+    fn dry_run_keeps_state_file() {
+        let path = std::env::temp_dir().join(format!("uaforge-engine-{}-dry-run", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        std::fs::write(&path, "saved by a real run\n").unwrap();
+        let _cleanup = scopeguard::guard(path.clone(), |p| {
+            let _ = std::fs::remove_file(p);
+        });
+
+        let mut h = Harness::new(&[
+            "--fw-dry-run",
+            "--fw-state-file",
+            &path,
+            "--fw-state-interval",
+            "1s",
+        ]);
+        h.non_http(5);
+        h.advance(Duration::from_secs(1));
+        h.engine.finish();
+
+        // 周期快照与退出时都不写，上次正式运行保存的条目保持原样
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "saved by a real run\n");
+    }
+
+    #[test]
+    fn batch_flushes_on_size_or_delay() {
+        let mut h = Harness::new(&[]);
+        let add = |h: &mut Harness, port: u16| {
+            h.engine.handle(Event::Add { src: SRC, ip: DST, port, timeout: 60 });
+        };
+
+        for port in 1..BATCH_SIZE_THRESHOLD as u16 {
+            add(&mut h, port);
+        }
+        assert!(h.backend.ops().is_empty());
+        add(&mut h, BATCH_SIZE_THRESHOLD as u16);
+        assert_eq!(h.backend.ops().len(), 1);
+        assert_eq!(h.added().len(), BATCH_SIZE_THRESHOLD);
+
+        add(&mut h, 9999);
+        h.advance(FLUSH / 2);
+        assert_eq!(h.backend.ops().len(), 1);
+        h.advance(FLUSH / 2);
+        assert_eq!(h.backend.ops().last(), Some(&Op::Add(vec![(h.key(9999), 60)])));
+    }
+}
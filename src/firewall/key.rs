@@ -0,0 +1,276 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// IPv6 前缀粒度固定按 /64 聚合（当前透明代理只处理 IPv4，仅作兜底）
+const IPV6_PREFIX_LEN: u8 = 64;
+
+/// 卸载粒度：决定集合条目和计分按什么聚合
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyMode {
+    /// 仅目标 IP（hash:ip / ipv4_addr）
+    Ip,
+    /// 目标 IP + 端口（hash:ip,port / ipv4_addr . inet_service）
+    IpPort,
+    /// 目标网段（hash:net / ipv4_addr flags interval）
+    Prefix(u8),
+    /// 源 IP + 目标 IP + 端口（hash:ip,port,ip / ipv4_addr . ipv4_addr . inet_service）
+    Tuple,
+}
+
+impl KeyMode {
+    pub fn parse(s: &str, prefix_len: u8) -> Result<Self, String> {
+        match s {
+            "ip" => Ok(KeyMode::Ip),
+            "ip-port" => Ok(KeyMode::IpPort),
+            "prefix" => {
+                if prefix_len == 0 || prefix_len > 32 {
+                    return Err(format!("invalid prefix length: {} (expected 1-32)", prefix_len));
+                }
+                Ok(KeyMode::Prefix(prefix_len))
+            }
+            "tuple" => Ok(KeyMode::Tuple),
+            _ => Err(format!("invalid offload key: {} (expected ip/ip-port/prefix/tuple)", s)),
+        }
+    }
+
+    /// 按当前粒度生成条目
+    pub fn key(&self, src: IpAddr, dst: IpAddr, port: u16) -> OffloadKey {
+        match *self {
+            KeyMode::Ip => OffloadKey::Ip(dst),
+            KeyMode::IpPort => OffloadKey::IpPort(dst, port),
+            KeyMode::Prefix(len) => {
+                let len = if dst.is_ipv4() { len } else { IPV6_PREFIX_LEN };
+                OffloadKey::Prefix(mask(dst, len), len)
+            }
+            KeyMode::Tuple => OffloadKey::Tuple { src, dst, port },
+        }
+    }
+
+    /// 条目是否属于当前粒度（粒度切换后旧状态不能写入新类型的集合）
+    pub fn matches(&self, key: &OffloadKey) -> bool {
+        matches!(
+            (self, key),
+            (KeyMode::Ip, OffloadKey::Ip(_))
+                | (KeyMode::IpPort, OffloadKey::IpPort(..))
+                | (KeyMode::Prefix(_), OffloadKey::Prefix(..))
+                | (KeyMode::Tuple, OffloadKey::Tuple { .. })
+        )
+    }
+}
+
+/// 卸载集合中的一个条目
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OffloadKey {
+    Ip(IpAddr),
+    IpPort(IpAddr, u16),
+    Prefix(IpAddr, u8),
+    Tuple { src: IpAddr, dst: IpAddr, port: u16 },
+}
+
+impl OffloadKey {
+    /// nft 元素写法，如 `1.2.3.4 . 443`
+    pub fn nft_element(&self) -> String {
+        match self {
+            OffloadKey::Ip(ip) => ip.to_string(),
+            OffloadKey::IpPort(ip, port) => format!("{ip} . {port}"),
+            OffloadKey::Prefix(net, len) => format!("{net}/{len}"),
+            OffloadKey::Tuple { src, dst, port } => format!("{src} . {dst} . {port}"),
+        }
+    }
+
+    /// ipset 元素写法，如 `1.2.3.4,443`；tuple 对应 hash:ip,port,ip 的 dst,port,src
+    pub fn ipset_element(&self) -> String {
+        match self {
+            OffloadKey::Ip(ip) => ip.to_string(),
+            OffloadKey::IpPort(ip, port) => format!("{ip},{port}"),
+            OffloadKey::Prefix(net, len) => format!("{net}/{len}"),
+            OffloadKey::Tuple { src, dst, port } => format!("{dst},{port},{src}"),
+        }
+    }
+
+    /// 解析 `nft list set` 中的元素（已去掉 timeout/expires 等修饰）
+    pub fn parse_nft(mode: KeyMode, s: &str) -> Option<Self> {
+        let parts: Vec<&str> = s.split(" . ").map(str::trim).collect();
+        let key = match (mode, parts.as_slice()) {
+            (KeyMode::Ip, [ip]) => OffloadKey::Ip(ip.parse().ok()?),
+            (KeyMode::IpPort, [ip, port]) => OffloadKey::IpPort(ip.parse().ok()?, port.parse().ok()?),
+            (KeyMode::Prefix(_), [net]) => parse_prefix(net)?,
+            (KeyMode::Tuple, [src, dst, port]) => OffloadKey::Tuple {
+                src: src.parse().ok()?,
+                dst: dst.parse().ok()?,
+                port: port.parse().ok()?,
+            },
+            _ => return None,
+        };
+        Some(key)
+    }
+
+    /// 解析 `ipset list` 中的成员，端口带协议前缀（`tcp:443`）
+    pub fn parse_ipset(mode: KeyMode, s: &str) -> Option<Self> {
+        let parts: Vec<&str> = s.split(',').collect();
+        let port = |p: &str| p.rsplit(':').next()?.parse::<u16>().ok();
+        let key = match (mode, parts.as_slice()) {
+            (KeyMode::Ip, [ip]) => OffloadKey::Ip(ip.parse().ok()?),
+            (KeyMode::IpPort, [ip, p]) => OffloadKey::IpPort(ip.parse().ok()?, port(p)?),
+            (KeyMode::Prefix(_), [net]) => parse_prefix(net)?,
+            (KeyMode::Tuple, [dst, p, src]) => OffloadKey::Tuple {
+                src: src.parse().ok()?,
+                dst: dst.parse().ok()?,
+                port: port(p)?,
+            },
+            _ => return None,
+        };
+        Some(key)
+    }
+}
+
+/// 状态文件与日志中使用的紧凑写法，可由 `FromStr` 解析回来
+impl fmt::Display for OffloadKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OffloadKey::Ip(ip) => write!(f, "{ip}"),
+            OffloadKey::IpPort(ip, port) => write!(f, "{}", SocketAddr::new(*ip, *port)),
+            OffloadKey::Prefix(net, len) => write!(f, "{net}/{len}"),
+            OffloadKey::Tuple { src, dst, port } => {
+                write!(f, "{src}->{}", SocketAddr::new(*dst, *port))
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for OffloadKey {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        if let Some((src, dst)) = s.split_once("->") {
+            let dst: SocketAddr = dst.parse().map_err(|_| ())?;
+            return Ok(OffloadKey::Tuple {
+                src: src.parse().map_err(|_| ())?,
+                dst: dst.ip(),
+                port: dst.port(),
+            });
+        }
+        if s.contains('/') {
+            return parse_prefix(s).ok_or(());
+        }
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(OffloadKey::IpPort(addr.ip(), addr.port()));
+        }
+        s.parse::<IpAddr>().map(OffloadKey::Ip).map_err(|_| ())
+    }
+}
+
+/// 解析网段；nft/ipset 列出 /32 条目时不带前缀长度，按单个主机处理
+fn parse_prefix(s: &str) -> Option<OffloadKey> {
+    let (net, len) = match s.split_once('/') {
+        Some((net, len)) => (net.parse::<IpAddr>().ok()?, len.parse::<u8>().ok()?),
+        None => {
+            let ip = s.parse::<IpAddr>().ok()?;
+            (ip, if ip.is_ipv4() { 32 } else { 128 })
+        }
+    };
+    Some(OffloadKey::Prefix(mask(net, len), len))
+}
+
+fn mask(ip: IpAddr, len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(v4);
+            let m = u32::MAX.checked_shl(32 - len.min(32) as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(bits & m))
+        }
+        IpAddr::V6(v6) => {
+            let bits = u128::from(v6);
+            let m = u128::MAX.checked_shl(128 - len.min(128) as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(bits & m))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+    const DST: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+
+    fn modes() -> [KeyMode; 4] {
+        [KeyMode::Ip, KeyMode::IpPort, KeyMode::Prefix(24), KeyMode::Tuple]
+    }
+
+    #[test]
+    fn parse_mode() {
+        assert_eq!(KeyMode::parse("ip", 24), Ok(KeyMode::Ip));
+        assert_eq!(KeyMode::parse("ip-port", 24), Ok(KeyMode::IpPort));
+        assert_eq!(KeyMode::parse("prefix", 16), Ok(KeyMode::Prefix(16)));
+        assert_eq!(KeyMode::parse("tuple", 24), Ok(KeyMode::Tuple));
+        assert!(KeyMode::parse("prefix", 0).is_err());
+        assert!(KeyMode::parse("prefix", 33).is_err());
+        assert!(KeyMode::parse("port", 24).is_err());
+    }
+
+    #[test]
+    fn key_per_mode() {
+        let keys: Vec<String> = modes().iter().map(|m| m.key(SRC, DST, 443).to_string()).collect();
+        assert_eq!(keys, ["1.2.3.4", "1.2.3.4:443", "1.2.3.0/24", "10.0.0.2->1.2.3.4:443"]);
+
+        // IPv6 固定按 /64 聚合
+        let v6: IpAddr = "2001:db8::1:2".parse().unwrap();
+        assert_eq!(KeyMode::Prefix(24).key(SRC, v6, 443).to_string(), "2001:db8::/64");
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        for mode in modes() {
+            for dst in [DST, v6] {
+                let key = mode.key(SRC, dst, 443);
+                assert_eq!(key.to_string().parse::<OffloadKey>(), Ok(key), "{key}");
+                assert!(mode.matches(&key));
+            }
+        }
+    }
+
+    #[test]
+    fn nft_round_trip() {
+        let elements: Vec<String> = modes().iter().map(|m| m.key(SRC, DST, 443).nft_element()).collect();
+        assert_eq!(elements, ["1.2.3.4", "1.2.3.4 . 443", "1.2.3.0/24", "10.0.0.2 . 1.2.3.4 . 443"]);
+        for mode in modes() {
+            let key = mode.key(SRC, DST, 443);
+            assert_eq!(OffloadKey::parse_nft(mode, &key.nft_element()), Some(key));
+        }
+        // 粒度不符的元素不解析
+        assert_eq!(OffloadKey::parse_nft(KeyMode::Ip, "1.2.3.4 . 443"), None);
+    }
+
+    #[test]
+    fn ipset_round_trip() {
+        let elements: Vec<String> = modes().iter().map(|m| m.key(SRC, DST, 443).ipset_element()).collect();
+        assert_eq!(elements, ["1.2.3.4", "1.2.3.4,443", "1.2.3.0/24", "1.2.3.4,443,10.0.0.2"]);
+
+        // ipset list 输出端口带协议前缀，/32 网段不带前缀长度
+        let listed = [
+            (KeyMode::Ip, "1.2.3.4"),
+            (KeyMode::IpPort, "1.2.3.4,tcp:443"),
+            (KeyMode::Prefix(24), "1.2.3.0/24"),
+            (KeyMode::Tuple, "1.2.3.4,tcp:443,10.0.0.2"),
+        ];
+        for (mode, member) in listed {
+            assert_eq!(OffloadKey::parse_ipset(mode, member), Some(mode.key(SRC, DST, 443)));
+        }
+        assert_eq!(
+            OffloadKey::parse_ipset(KeyMode::Prefix(24), "1.2.3.4"),
+            Some(OffloadKey::Prefix(DST, 32))
+        );
+    }
+
+    #[test]
+    fn matches_only_same_mode() {
+        let key = KeyMode::IpPort.key(SRC, DST, 443);
+        assert!(KeyMode::IpPort.matches(&key));
+        assert!(!KeyMode::Ip.matches(&key));
+        assert!(!KeyMode::Tuple.matches(&key));
+        // 前缀长度变化不影响类型匹配
+        assert!(KeyMode::Prefix(16).matches(&KeyMode::Prefix(24).key(SRC, DST, 443)));
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex as SharedMutex;
+
+use crate::config::FirewallConfig;
+use crate::stats::Stats;
+
+mod backend;
+mod clock;
+mod engine;
+mod key;
+mod score;
+mod state;
+
+use engine::Engine;
+pub use key::{KeyMode, OffloadKey};
+
+/// 已写入集合的条目及其过期时间（None 表示永久），由后台线程维护，代理侧只读
+type Offloaded = Arc<SharedMutex<HashMap<OffloadKey, Option<Instant>>>>;
+
+// 管理接口等待后台线程应答的上限，集合命令卡住时不至于一直阻塞
+const QUERY_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Clone)]
+pub struct FirewallManager {
+    inner: Arc<Inner>,
+}
+
+// 注意：不能为 Inner 派生 Debug，因为 JoinHandle 不实现 Debug
+struct Inner {
+    config: FirewallConfig,
+    mode: KeyMode,
+    offloaded: Offloaded,
+    tx: mpsc::Sender<Event>,
+    handle: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+#[derive(Debug)]
+enum Event {
+    Http { src: IpAddr, ip: IpAddr, port: u16 },
+    NonHttp { src: IpAddr, ip: IpAddr, port: u16 },
+    Add { src: IpAddr, ip: IpAddr, port: u16, timeout: u32 },
+    List { reply: mpsc::Sender<Vec<(OffloadKey, u64)>> },
+    Remove { keys: Vec<OffloadKey>, reply: mpsc::Sender<io::Result<usize>> },
+    Stop,
+}
+
+impl FirewallManager {
+    pub fn new(cfg: FirewallConfig, stats: Arc<Stats>) -> Self {
+        // 启动时已在 Config::from_args 中校验
+        let mode = cfg.key_mode().unwrap_or(KeyMode::IpPort);
+        let backend = backend::from_config(&cfg, mode);
+        Self::spawn(cfg, mode, backend, stats)
+    }
+
+    /// 非演练模式也只写入内存后端，供无 root 环境下的代理测试使用
+    #[cfg(test)]
+    pub fn in_memory(cfg: FirewallConfig, stats: Arc<Stats>) -> Self {
+        let mode = cfg.key_mode().unwrap_or(KeyMode::IpPort);
+        let backend = backend::from_config(&cfg, mode)
+            .map(|_| Box::new(backend::MemoryBackend::new()) as Box<dyn backend::Backend>);
+        Self::spawn(cfg, mode, backend, stats)
+    }
+
+    fn spawn(
+        cfg: FirewallConfig,
+        mode: KeyMode,
+        backend: Option<Box<dyn backend::Backend>>,
+        stats: Arc<Stats>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel::<Event>();
+        let offloaded = Offloaded::default();
+
+        let worker_config = cfg.clone();
+        let worker_offloaded = offloaded.clone();
+        let handle = thread::spawn(move || worker(worker_config, mode, backend, worker_offloaded, rx, stats));
+
+        let inner = Arc::new(Inner {
+            config: cfg,
+            mode,
+            offloaded,
+            tx,
+            handle: Mutex::new(Some(handle)),
+        });
+
+        Self { inner }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.inner.config.enable_firewall_set()
+    }
+
+    /// 演练模式：只记录决策，不写入 ipset/nft
+    pub fn dry_run(&self) -> bool {
+        self.inner.config.fw_dry_run
+    }
+
+    pub fn report_http(&self, src: IpAddr, ip: IpAddr, port: u16) {
+        if !self.enabled() {
+            return;
+        }
+        let _ = self.inner.tx.send(Event::Http { src, ip, port });
+    }
+
+    pub fn report_non_http(&self, src: IpAddr, ip: IpAddr, port: u16) {
+        if !self.enabled() || !self.inner.config.fw_bypass {
+            return;
+        }
+        let _ = self.inner.tx.send(Event::NonHttp { src, ip, port });
+    }
+
+    pub fn add(&self, src: IpAddr, ip: IpAddr, port: u16, timeout: u32) {
+        if !self.enabled() {
+            return;
+        }
+        let _ = self.inner.tx.send(Event::Add { src, ip, port, timeout });
+    }
+
+    /// 条目已写入集合且未过期，之后的新连接不再经过代理。
+    /// 批次约 100ms 后才落地，此前断开连接只会让客户端重连回来再次命中。
+    pub fn offloaded(&self, src: IpAddr, ip: IpAddr, port: u16) -> bool {
+        if !self.enabled() {
+            return false;
+        }
+        let key = self.inner.mode.key(src, ip, port);
+        let now = Instant::now();
+        self.inner
+            .offloaded
+            .lock()
+            .get(&key)
+            .is_some_and(|expires| expires.is_none_or(|t| now < t))
+    }
+
+    /// 已卸载条目及剩余超时秒（0 表示永久）。会阻塞等待后台线程应答。
+    pub fn list_offloaded(&self) -> io::Result<Vec<(OffloadKey, u64)>> {
+        if !self.enabled() {
+            return Err(io::Error::other("firewall offload is disabled"));
+        }
+        let (reply, rx) = mpsc::channel();
+        self.query(Event::List { reply }, rx)
+    }
+
+    /// 从集合中删除条目，并清除其计分画像。会阻塞等待后台线程应答。
+    pub fn remove_offloaded(&self, keys: Vec<OffloadKey>) -> io::Result<usize> {
+        if !self.enabled() {
+            return Err(io::Error::other("firewall offload is disabled"));
+        }
+        let (reply, rx) = mpsc::channel();
+        self.query(Event::Remove { keys, reply }, rx)?
+    }
+
+    fn query<T>(&self, evt: Event, rx: mpsc::Receiver<T>) -> io::Result<T> {
+        self.inner
+            .tx
+            .send(evt)
+            .map_err(|_| io::Error::other("firewall worker stopped"))?;
+        rx.recv_timeout(Duration::from_secs(QUERY_TIMEOUT_SECS))
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "firewall worker did not respond"))
+    }
+
+    /// 停止后台线程并等待其写出最后的批次和状态文件
+    pub fn shutdown(&self) {
+        // 发送停止信号
+        let _ = self.inner.tx.send(Event::Stop);
+
+        // 等待后台线程结束
+        if let Ok(mut guard) = self.inner.handle.lock() {
+            if let Some(handle) = guard.take() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
+impl Drop for FirewallManager {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn worker(
+    fw_config: FirewallConfig,
+    mode: KeyMode,
+    backend: Option<Box<dyn backend::Backend>>,
+    offloaded: Offloaded,
+    rx: mpsc::Receiver<Event>,
+    stats: Arc<Stats>,
+) {
+    let mut engine = Engine::new(fw_config, mode, backend, Box::new(clock::SystemClock), stats, offloaded);
+    engine.restore();
+
+    loop {
+        let evt = match engine.next_timeout() {
+            Some(t) => rx.recv_timeout(t).ok(),
+            None => rx.recv().ok(),
+        };
+
+        match evt {
+            Some(Event::Stop) => break,
+            Some(e) => engine.handle(e),
+            None => {}
+        }
+
+        engine.tick();
+    }
+
+    engine.finish();
+}
@@ -0,0 +1,158 @@
+use std::time::{Duration, Instant};
+
+// 窗口切分的桶数：内存固定为 8 对计数，精度为窗口的 1/8
+const WINDOW_BUCKETS: usize = 8;
+
+/// 滑动窗口计数：统计最近一个窗口内的非 HTTP / HTTP 连接数。
+/// 过期的桶会被自动丢弃，分散在很长时间里的零星连接不会累积成卸载决策。
+#[derive(Debug, Clone)]
+pub(super) struct ScoreWindow {
+    // 每个桶：(非 HTTP 次数, HTTP 次数)
+    buckets: [(u32, u32); WINDOW_BUCKETS],
+    head: usize,
+    head_start: Instant,
+}
+
+impl ScoreWindow {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            buckets: [(0, 0); WINDOW_BUCKETS],
+            head: 0,
+            head_start: now,
+        }
+    }
+
+    /// 以给定计数初始化（用于状态恢复，计数全部放入最新的桶）
+    pub fn with_counts(now: Instant, non_http: u32, http: u32) -> Self {
+        let mut w = Self::new(now);
+        w.buckets[0] = (non_http, http);
+        w
+    }
+
+    pub fn record_non_http(&mut self, now: Instant, window: Duration) {
+        self.advance(now, window);
+        let b = &mut self.buckets[self.head];
+        b.0 = b.0.saturating_add(1);
+    }
+
+    pub fn record_http(&mut self, now: Instant, window: Duration) {
+        self.advance(now, window);
+        let b = &mut self.buckets[self.head];
+        b.1 = b.1.saturating_add(1);
+    }
+
+    /// 窗口内的 (非 HTTP 次数, HTTP 次数)
+    pub fn counts(&self, now: Instant, window: Duration) -> (u32, u32) {
+        let steps = self.steps(now, window);
+        if steps >= WINDOW_BUCKETS {
+            return (0, 0);
+        }
+        (0..WINDOW_BUCKETS - steps)
+            .map(|i| self.buckets[(self.head + WINDOW_BUCKETS - i) % WINDOW_BUCKETS])
+            .fold((0u32, 0u32), |(n, h), (bn, bh)| {
+                (n.saturating_add(bn), h.saturating_add(bh))
+            })
+    }
+
+    fn advance(&mut self, now: Instant, window: Duration) {
+        let steps = self.steps(now, window);
+        if steps == 0 {
+            return;
+        }
+        if steps >= WINDOW_BUCKETS {
+            self.buckets = [(0, 0); WINDOW_BUCKETS];
+            self.head = 0;
+            self.head_start = now;
+            return;
+        }
+        for _ in 0..steps {
+            self.head = (self.head + 1) % WINDOW_BUCKETS;
+            self.buckets[self.head] = (0, 0);
+        }
+        self.head_start += bucket_width(window) * steps as u32;
+    }
+
+    fn steps(&self, now: Instant, window: Duration) -> usize {
+        let elapsed = now.saturating_duration_since(self.head_start);
+        let steps = elapsed.as_millis() / bucket_width(window).as_millis();
+        steps.min(WINDOW_BUCKETS as u128) as usize
+    }
+}
+
+fn bucket_width(window: Duration) -> Duration {
+    (window / WINDOW_BUCKETS as u32).max(Duration::from_millis(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(80);
+    const BUCKET: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn counts_within_window() {
+        let t0 = Instant::now();
+        let mut w = ScoreWindow::new(t0);
+        w.record_non_http(t0, WINDOW);
+        w.record_non_http(t0 + Duration::from_secs(5), WINDOW);
+        w.record_http(t0 + Duration::from_secs(9), WINDOW);
+        assert_eq!(w.counts(t0 + Duration::from_secs(9), WINDOW), (2, 1));
+    }
+
+    #[test]
+    fn buckets_expire_one_by_one() {
+        let t0 = Instant::now();
+        let mut w = ScoreWindow::new(t0);
+        // 每个桶记一次非 HTTP，偶数桶再记一次 HTTP
+        for i in 0..WINDOW_BUCKETS as u32 {
+            let t = t0 + BUCKET * i;
+            w.record_non_http(t, WINDOW);
+            if i % 2 == 0 {
+                w.record_http(t, WINDOW);
+            }
+        }
+        let last = t0 + BUCKET * (WINDOW_BUCKETS as u32 - 1);
+        assert_eq!(w.counts(last, WINDOW), (8, 4));
+
+        // 每跨过一个桶边界，最旧的桶过期
+        assert_eq!(w.counts(last + BUCKET, WINDOW), (7, 3));
+        assert_eq!(w.counts(last + BUCKET * 2, WINDOW), (6, 3));
+        assert_eq!(w.counts(last + BUCKET * 3 - Duration::from_millis(1), WINDOW), (6, 3));
+        assert_eq!(w.counts(last + BUCKET * 3, WINDOW), (5, 2));
+        assert_eq!(w.counts(last + WINDOW, WINDOW), (0, 0));
+    }
+
+    #[test]
+    fn ratio_shifts_as_http_ages_out() {
+        let t0 = Instant::now();
+        let mut w = ScoreWindow::new(t0);
+        for _ in 0..4 {
+            w.record_http(t0, WINDOW);
+        }
+        let t1 = t0 + BUCKET * 4;
+        for _ in 0..4 {
+            w.record_non_http(t1, WINDOW);
+        }
+        assert_eq!(w.counts(t1, WINDOW), (4, 4));
+
+        // 早期的 HTTP 滑出窗口后只剩非 HTTP
+        let t2 = t0 + WINDOW;
+        assert_eq!(w.counts(t2, WINDOW), (4, 0));
+        w.record_non_http(t2, WINDOW);
+        assert_eq!(w.counts(t2, WINDOW), (5, 0));
+    }
+
+    #[test]
+    fn long_idle_resets() {
+        let t0 = Instant::now();
+        let mut w = ScoreWindow::with_counts(t0, 10, 3);
+        assert_eq!(w.counts(t0, WINDOW), (10, 3));
+
+        let later = t0 + WINDOW * 5;
+        w.record_non_http(later, WINDOW);
+        assert_eq!(w.counts(later, WINDOW), (1, 0));
+        assert_eq!(w.counts(later + BUCKET * 7, WINDOW), (1, 0));
+        assert_eq!(w.counts(later + BUCKET * 8, WINDOW), (0, 0));
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::engine::PortProfile;
+use super::key::OffloadKey;
+use super::score::ScoreWindow;
+
+// 状态文件格式（纯文本，每行一条记录）：
+//   saved_at <unix 秒>
+//   offload <key> <剩余超时秒，0 表示永久>
+//   profile <key> <窗口内非 HTTP 次数> <窗口内 HTTP 次数> <http_lock 剩余秒|-> <决策剩余秒|-> <距上次事件秒>
+// key 为 OffloadKey 的紧凑写法（1.2.3.4 / 1.2.3.4:443 / 1.2.3.0/24 / 10.0.0.2->1.2.3.4:443）。
+// Instant 无法跨进程保存，因此全部换算为相对保存时刻的秒数，加载时再扣除停机时长。
+const STATE_VERSION: u32 = 1;
+const STATE_HEADER_PREFIX: &str = "# uaforge firewall state v";
+
+/// 从状态文件恢复出的数据
+pub(super) struct Snapshot {
+    /// (条目, 剩余超时秒)，0 表示永久
+    pub offloaded: Vec<(OffloadKey, u32)>,
+    pub profiles: Vec<(OffloadKey, PortProfile)>,
+}
+
+/// 保存已卸载条目和进行中的端口画像
+pub(super) fn save(
+    path: &str,
+    now: Instant,
+    window: Duration,
+    offloaded: &HashMap<OffloadKey, Option<Instant>>,
+    profiles: &HashMap<OffloadKey, PortProfile>,
+) -> io::Result<()> {
+    let mut content = String::new();
+    content.push_str(&format!("{STATE_HEADER_PREFIX}{STATE_VERSION}\n"));
+    content.push_str(&format!("saved_at {}\n", unix_now()));
+
+    for (key, expires) in offloaded {
+        let remaining = match expires {
+            Some(t) => {
+                let secs = t.saturating_duration_since(now).as_secs();
+                if secs == 0 {
+                    continue;
+                }
+                secs.min(u32::MAX as u64)
+            }
+            None => 0,
+        };
+        content.push_str(&format!("offload {key} {remaining}\n"));
+    }
+
+    for (key, p) in profiles {
+        let (non_http, http) = p.window.counts(now, window);
+        content.push_str(&format!(
+            "profile {key} {non_http} {http} {} {} {}\n",
+            fmt_remaining(p.http_lock_expires, now),
+            fmt_remaining(p.decision_deadline, now),
+            now.saturating_duration_since(p.last_event).as_secs(),
+        ));
+    }
+
+    // 原子写入：先写临时文件，再 rename
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, &content)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// 读取状态文件，按停机时长修正剩余时间
+pub(super) fn load(path: &str, now: Instant, window: Duration) -> io::Result<Snapshot> {
+    let content = fs::read_to_string(path)?;
+
+    let mut snapshot = Snapshot {
+        offloaded: Vec::new(),
+        profiles: Vec::new(),
+    };
+    let mut elapsed = 0u64;
+    let version = content
+        .lines()
+        .next()
+        .and_then(|l| l.strip_prefix(STATE_HEADER_PREFIX))
+        .and_then(|v| v.trim().parse::<u32>().ok());
+    if version != Some(STATE_VERSION) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported state file version"));
+    }
+
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["saved_at", ts] => {
+                let saved_at = ts.parse::<u64>().unwrap_or(0);
+                elapsed = unix_now().saturating_sub(saved_at);
+            }
+            ["offload", key, remaining] => {
+                let (Ok(key), Ok(remaining)) = (key.parse::<OffloadKey>(), remaining.parse::<u64>())
+                else {
+                    continue;
+                };
+                if remaining == 0 {
+                    snapshot.offloaded.push((key, 0));
+                } else if remaining > elapsed {
+                    snapshot.offloaded.push((key, (remaining - elapsed) as u32));
+                }
+            }
+            ["profile", key, non_http, http, lock, decision, age] => {
+                let (Ok(key), Ok(non_http), Ok(http), Ok(age)) = (
+                    key.parse::<OffloadKey>(),
+                    non_http.parse::<u32>(),
+                    http.parse::<u32>(),
+                    age.parse::<u64>(),
+                ) else {
+                    continue;
+                };
+                let idle = Duration::from_secs(age.saturating_add(elapsed));
+                let last_event = now.checked_sub(idle).unwrap_or(now);
+                // 停机时间超过窗口的计数已全部过期
+                let window_counts = if idle < window {
+                    ScoreWindow::with_counts(now, non_http, http)
+                } else {
+                    ScoreWindow::new(now)
+                };
+                let profile = PortProfile {
+                    window: window_counts,
+                    http_lock_expires: parse_remaining(lock, elapsed)
+                        .filter(|r| *r > 0)
+                        .map(|r| now + Duration::from_secs(r)),
+                    last_event,
+                    // 停机期间已到期的决策立即执行
+                    decision_deadline: parse_remaining(decision, elapsed)
+                        .map(|r| now + Duration::from_secs(r)),
+                };
+                snapshot.profiles.push((key, profile));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(snapshot)
+}
+
+fn fmt_remaining(t: Option<Instant>, now: Instant) -> String {
+    match t {
+        Some(t) => t.saturating_duration_since(now).as_secs().to_string(),
+        None => "-".to_string(),
+    }
+}
+
+fn parse_remaining(s: &str, elapsed: u64) -> Option<u64> {
+    s.parse::<u64>().ok().map(|r| r.saturating_sub(elapsed))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(600);
+
+    /// 每个测试使用独立的临时文件，结束时删除
+    struct TempFile(String);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("uaforge-state-{}-{}", std::process::id(), name));
+            Self(path.to_string_lossy().into_owned())
+        }
+
+        fn write(name: &str, content: &str) -> Self {
+            let f = Self::new(name);
+            fs::write(&f.0, content).unwrap();
+            f
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn key(s: &str) -> OffloadKey {
+        s.parse().unwrap()
+    }
+
+    /// 保存与加载之间可能跨过一秒边界，剩余时间允许少 1 秒
+    fn assert_remaining(actual: u64, expected: u64) {
+        assert!(
+            actual == expected || actual + 1 == expected,
+            "remaining {actual}, expected {expected}"
+        );
+    }
+
+    fn profile<'a>(snapshot: &'a Snapshot, k: &str) -> &'a PortProfile {
+        &snapshot.profiles.iter().find(|(pk, _)| *pk == key(k)).unwrap().1
+    }
+
+    #[test]
+    fn round_trip_all_key_kinds() {
+        let f = TempFile::new("round-trip");
+        let now = Instant::now();
+        let offloaded = HashMap::from([
+            (key("1.2.3.4"), None),
+            (key("1.2.3.4:443"), Some(now + Duration::from_secs(100))),
+            (key("1.2.3.0/24"), Some(now + Duration::from_secs(200))),
+            (key("10.0.0.2->1.2.3.4:443"), None),
+            // 已过期的条目不写入
+            (key("5.6.7.8:80"), Some(now)),
+        ]);
+        let mut window = ScoreWindow::new(now);
+        for _ in 0..3 {
+            window.record_non_http(now, WINDOW);
+        }
+        window.record_http(now, WINDOW);
+        let profiles = HashMap::from([(
+            key("1.2.3.4:8443"),
+            PortProfile {
+                window,
+                http_lock_expires: Some(now + Duration::from_secs(50)),
+                last_event: now - Duration::from_secs(5),
+                decision_deadline: None,
+            },
+        )]);
+
+        save(&f.0, now, WINDOW, &offloaded, &profiles).unwrap();
+        let mut snapshot = load(&f.0, now, WINDOW).unwrap();
+
+        snapshot.offloaded.sort_by_key(|(k, _)| k.to_string());
+        let keys: Vec<String> = snapshot.offloaded.iter().map(|(k, _)| k.to_string()).collect();
+        assert_eq!(keys, ["1.2.3.0/24", "1.2.3.4", "1.2.3.4:443", "10.0.0.2->1.2.3.4:443"]);
+        assert_remaining(snapshot.offloaded[0].1 as u64, 200);
+        assert_eq!(snapshot.offloaded[1].1, 0);
+        assert_remaining(snapshot.offloaded[2].1 as u64, 100);
+        assert_eq!(snapshot.offloaded[3].1, 0);
+
+        let p = profile(&snapshot, "1.2.3.4:8443");
+        assert_eq!(p.window.counts(now, WINDOW), (3, 1));
+        assert_remaining(p.http_lock_expires.unwrap().duration_since(now).as_secs(), 50);
+        assert!(p.decision_deadline.is_none());
+        assert!(now.duration_since(p.last_event) >= Duration::from_secs(5));
+    }
+
+    #[test]
+    fn deducts_downtime() {
+        let f = TempFile::write(
+            "downtime",
+            &format!(
+                "# uaforge firewall state v1\n\
+                 saved_at {}\n\
+                 offload 1.2.3.4:443 100\n\
+                 offload 1.2.3.4:80 1000\n\
+                 profile 1.2.3.4:8443 3 1 - 10 5\n\
+                 profile 1.2.3.4:8080 3 1 - - 500\n",
+                unix_now() - 200
+            ),
+        );
+        let now = Instant::now();
+        let snapshot = load(&f.0, now, WINDOW).unwrap();
+
+        assert_eq!(snapshot.offloaded.len(), 1);
+        assert_eq!(snapshot.offloaded[0].0, key("1.2.3.4:80"));
+        assert!((799..=800).contains(&snapshot.offloaded[0].1));
+
+        // 停机期间到期的决策立即执行
+        let p = profile(&snapshot, "1.2.3.4:8443");
+        assert_eq!(p.window.counts(now, WINDOW), (3, 1));
+        assert_eq!(p.decision_deadline, Some(now));
+
+        // 空闲超过窗口的计数清零
+        let p = profile(&snapshot, "1.2.3.4:8080");
+        assert_eq!(p.window.counts(now, WINDOW), (0, 0));
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        let f = TempFile::write(
+            "malformed",
+            "# uaforge firewall state v1\noffload nonsense 10\noffload 1.2.3.4:443\nprofile 1.2.3.4:443 x 0 - - 0\n",
+        );
+        let snapshot = load(&f.0, Instant::now(), WINDOW).unwrap();
+        assert!(snapshot.offloaded.is_empty());
+        assert!(snapshot.profiles.is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_version() {
+        let files = [
+            ("v2", "# uaforge firewall state v2\noffload 1.2.3.4:443 0\n"),
+            ("no-header", "offload 1.2.3.4:443 0\n"),
+        ];
+        for (name, content) in files {
+            let f = TempFile::write(name, content);
+            let err = load(&f.0, Instant::now(), WINDOW).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -1,34 +1,42 @@
 use std::borrow::Cow;
 use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use hyper::Request;
-use hyper::header::{HeaderValue, USER_AGENT};
+use hyper::header::{HeaderValue, HOST, USER_AGENT};
 
-use crate::config::Config;
+use crate::config::{Config, DropAction};
 use crate::stats::Stats;
 use crate::firewall::FirewallManager;
 use crate::logger;
-use crate::lru::{Cache, CacheDecision};
-use parking_lot::Mutex;
+use crate::lru::{Cache, CacheDecision, Lookup};
+use parking_lot::RwLock;
 use regex::Regex;
 
 pub struct HttpHandler {
-    config: Config,
+    rules: RwLock<Arc<Rules>>,
     stats: Arc<Stats>,
     fw: Arc<FirewallManager>,
-    cache: Option<Arc<Mutex<Cache>>>,
+    cache: Option<Cache>,
+}
+
+/// modify_request 的处理结果
+pub enum Outcome {
+    /// 转发请求（UA 可能已改写）
+    Forward(Request<hyper::body::Incoming>),
+    /// 防火墙白名单命中且启用了 fw_drop：按动作结束当前连接
+    Drop(DropAction, Request<hyper::body::Incoming>),
+}
+
+/// 匹配规则及其预编译结果，可在运行时整体替换（管理接口 reload）
+struct Rules {
+    config: Config,
     regex_cache: Option<Regex>,
     user_agent_header: HeaderValue,
 }
 
-impl HttpHandler {
-    pub fn new(config: Config, stats: Arc<Stats>, fw: Arc<FirewallManager>) -> Result<Self, String> {
-        let cache = if config.cache_size > 0 {
-            Some(Arc::new(Mutex::new(Cache::new(config.cache_size))))
-        } else {
-            None
-        };
-
+impl Rules {
+    fn new(config: Config) -> Result<Self, String> {
         // Pre-compile regex if in Regex mode - fail fast on invalid pattern
         let regex_cache = if let crate::config::MatchMode::Regex { pattern, .. } = &config.match_mode {
             match Regex::new(pattern) {
@@ -45,22 +53,23 @@ impl HttpHandler {
         let user_agent_header = HeaderValue::from_str(&config.user_agent)
             .unwrap_or_else(|_| HeaderValue::from_static("UAForge"));
 
-        Ok(Self { config, stats, fw, cache, regex_cache, user_agent_header })
+        Ok(Self { config, regex_cache, user_agent_header })
     }
 
-    /// 从缓存中获取值
-    fn cache_get(&self, key: &str) -> Option<CacheDecision> {
-        self.cache.as_ref()?.lock().get(key)
-    }
-
-    /// 向缓存中写入值
-    fn cache_put(&self, key: &str, value: CacheDecision) {
-        if let Some(cache) = &self.cache {
-            cache.lock().put(key.to_string(), value);
+    /// 缓存决策的有效期。防火墙白名单决策与防火墙条目同时过期，
+    /// 之后的请求重新走白名单匹配并再次卸载；超时为 0（永久）时按普通决策处理。
+    fn cache_ttl(&self, decision: CacheDecision) -> Option<Duration> {
+        let fw_timeout = self.config.firewall.fw_timeout;
+        match decision {
+            CacheDecision::FwWhitelist if fw_timeout > 0 => {
+                Some(Duration::from_secs(u64::from(fw_timeout)))
+            }
+            _ => self.config.cache_ttl,
         }
     }
 
     /// 判断 UA 是否需要修改（规则匹配）
+    #[allow(clippy::unnecessary_map_or)]
     fn should_modify_ua(&self, ua: &str) -> bool {
         use crate::config::MatchMode;
 
@@ -70,73 +79,186 @@ impl HttpHandler {
             MatchMode::Regex { .. } => self.regex_cache.as_ref().map_or(false, |re| re.is_match(ua)),
         }
     }
+}
+
+impl HttpHandler {
+    pub fn new(config: Config, stats: Arc<Stats>, fw: Arc<FirewallManager>) -> Result<Self, String> {
+        let cache = if config.cache_size > 0 {
+            let cache = Cache::new(
+                config.cache_size,
+                config.cache_shards,
+                config.cache_max_bytes,
+                config.cache_verify,
+            );
+            logger::log_at(
+                logger::Level::Debug,
+                "handler",
+                None,
+                format_args!(
+                    "decision cache: {} entries, {} bytes budget, {} shards",
+                    config.cache_size,
+                    config.cache_max_bytes,
+                    cache.shard_count()
+                ),
+            );
+            Some(cache)
+        } else {
+            None
+        };
+
+        let rules = RwLock::new(Arc::new(Rules::new(config)?));
+
+        Ok(Self { rules, stats, fw, cache })
+    }
+
+    /// 当前生效的配置
+    pub fn config(&self) -> Config {
+        self.rules.read().config.clone()
+    }
+
+    /// 上游失败时错误响应的自定义响应体
+    pub fn error_body(&self) -> Option<String> {
+        self.rules.read().config.error_body.clone()
+    }
+
+    pub fn upstream_timeout(&self) -> Option<Duration> {
+        self.rules.read().config.upstream_timeout
+    }
+
+    /// 替换 UA 匹配规则（目标 UA、白名单、匹配模式、防火墙 UA 白名单）。
+    /// 端口、缓存大小、防火墙集合等需要重启才能生效，这里保持不变。
+    /// 规则变化后旧的缓存决策不再可信，一并清空。
+    pub fn reload_rules(&self, new: &Config) -> Result<(), String> {
+        let mut config = self.config();
+        config.user_agent = new.user_agent.clone();
+        config.whitelist = new.whitelist.clone();
+        config.match_mode = new.match_mode.clone();
+        config.firewall.fw_ua_w = new.firewall.fw_ua_w.clone();
+
+        let rules = Rules::new(config)?;
+        *self.rules.write() = Arc::new(rules);
+        self.clear_cache();
+        Ok(())
+    }
+
+    /// 清空决策缓存，返回清除的条目数
+    pub fn clear_cache(&self) -> usize {
+        let Some(cache) = &self.cache else {
+            return 0;
+        };
+        let n = cache.len();
+        cache.clear();
+        n
+    }
+
+    /// 缓存条目数及估算内存占用（字节）
+    pub fn cache_usage(&self) -> (usize, usize) {
+        self.cache.as_ref().map_or((0, 0), |c| (c.len(), c.bytes()))
+    }
+
+    /// 从缓存中获取值，过期条目视为未命中
+    fn cache_get(&self, key: &str) -> Option<CacheDecision> {
+        match self.cache.as_ref()?.get(key) {
+            Lookup::Hit(decision) => Some(decision),
+            Lookup::Miss => None,
+            Lookup::Expired => {
+                self.stats.inc_cache_expired();
+                None
+            }
+        }
+    }
+
+    /// 向缓存中写入值
+    fn cache_put(&self, rules: &Rules, key: &str, value: CacheDecision) {
+        if let Some(cache) = &self.cache {
+            cache.put(key, value, rules.cache_ttl(value));
+        }
+    }
 
     /// 修改 HTTP 请求的 User-Agent（流式版本）
     pub async fn modify_request(
         &self,
         mut req: Request<hyper::body::Incoming>,
+        client_ip: IpAddr,
         dest_ip: IpAddr,
         dest_port: u16,
-    ) -> Result<Request<hyper::body::Incoming>, Box<dyn std::error::Error + Send + Sync>> {
-        self.fw.report_http(dest_ip, dest_port);
+    ) -> Outcome {
+        self.fw.report_http(client_ip, dest_ip, dest_port);
         self.stats.inc_http_requests();
+        self.stats.clients().inc_requests(client_ip);
+        let rules = self.rules.read().clone();
+
+        if let Some(host) = req.headers().get(HOST).and_then(|v| v.to_str().ok()) {
+            self.stats.top().record_host(host);
+        }
 
         // Extract UA as Cow (zero-copy when possible)
         let original_ua: Cow<'_, str> = match req.headers().get(USER_AGENT) {
             Some(v) => match v.to_str() {
                 Ok(s) => Cow::Borrowed(s),
-                Err(_) => return Ok(req),
+                Err(_) => return Outcome::Forward(req),
             },
-            None => return Ok(req),
+            None => return Outcome::Forward(req),
         };
 
         if original_ua.is_empty() {
-            return Ok(req);
+            return Outcome::Forward(req);
         }
 
         // 1. 检查 UA 白名单（最高优先级 - 直接放行）
-        if !self.config.whitelist.is_empty() {
-            for keyword in &self.config.whitelist {
+        if !rules.config.whitelist.is_empty() {
+            for keyword in &rules.config.whitelist {
                 if original_ua.contains(keyword.as_str()) {
-                    logger::log(
+                    self.stats.inc_whitelist_hits();
+                    self.stats.top().record_ua(&original_ua, false);
+                    logger::log_at(
                         logger::Level::Debug,
+                        "handler",
+                        Some(client_ip),
                         format_args!("UA whitelist hit: {} (keyword: {})", original_ua, keyword)
                     );
-                    return Ok(req);
+                    return Outcome::Forward(req);
                 }
             }
         }
 
         // 2. 检查防火墙 UA 白名单（次优先级 - 卸载到防火墙）
-        if self.fw.enabled() && !self.config.firewall.fw_ua_w.is_empty() {
-            // 先检查缓存，避免重复添加防火墙规则
-            if let Some(cached) = self.cache_get(&original_ua) {
-                if cached == CacheDecision::FwWhitelist {
-                    return Ok(req);
-                }
-            }
-
-            // 检查是否在白名单中
-            for keyword in &self.config.firewall.fw_ua_w {
-                if original_ua.contains(keyword.as_str()) {
-                    logger::log(
-                        logger::Level::Info,
-                        format_args!("Firewall UA whitelist hit: {} (keyword: {})", original_ua, keyword)
-                    );
-
-                    self.fw.add(dest_ip, dest_port, self.config.firewall.fw_timeout);
-                    self.cache_put(&original_ua, CacheDecision::FwWhitelist);
-
-                    if self.config.firewall.fw_drop {
+        if self.fw.enabled() && !rules.config.firewall.fw_ua_w.is_empty() {
+            // 缓存只省去关键字匹配；卸载条目按目标生成，同一 UA 访问新目标时仍要写入
+            let hit = self.cache_get(&original_ua) == Some(CacheDecision::FwWhitelist)
+                || match rules.config.firewall.fw_ua_w.iter().find(|kw| original_ua.contains(kw.as_str())) {
+                    Some(keyword) => {
                         logger::log(
                             logger::Level::Info,
-                            format_args!("Dropping connection for {}:{} to force bypass", dest_ip, dest_port)
+                            format_args!("Firewall UA whitelist hit: {} (keyword: {})", original_ua, keyword)
                         );
-                        return Err("Connection dropped: UA whitelist match (will bypass on reconnect)".into());
+                        self.cache_put(&rules, &original_ua, CacheDecision::FwWhitelist);
+                        true
                     }
+                    None => false,
+                };
+
+            if hit {
+                self.stats.inc_fw_whitelist_hits();
+                self.stats.top().record_ua(&original_ua, false);
+                self.fw.add(client_ip, dest_ip, dest_port, rules.config.firewall.fw_timeout);
 
-                    return Ok(req);
+                // 演练模式下不会真正卸载，断开连接只会让客户端反复失败；
+                // 条目仍在待写批次时同理，先照常转发，等集合生效后再断开
+                if rules.config.firewall.fw_drop
+                    && !self.fw.dry_run()
+                    && self.fw.offloaded(client_ip, dest_ip, dest_port)
+                {
+                    let action = rules.config.firewall.fw_drop_action;
+                    self.stats.inc_fw_drops();
+                    logger::log(
+                        logger::Level::Info,
+                        format_args!("Dropping connection for {}:{} to force bypass ({:?})", dest_ip, dest_port, action)
+                    );
+                    return Outcome::Drop(action, req);
                 }
+
+                return Outcome::Forward(req);
             }
         }
 
@@ -152,7 +274,7 @@ impl HttpHandler {
             }
         } else {
             // 缓存未命中 - 执行规则匹配
-            self.should_modify_ua(&original_ua)
+            rules.should_modify_ua(&original_ua)
         };
 
         // 如果需要修改
@@ -160,25 +282,36 @@ impl HttpHandler {
             // 在修改 req 之前，将 original_ua 转为 owned 以释放借用
             let ua_owned = original_ua.into_owned();
 
-            req.headers_mut().insert(USER_AGENT, self.user_agent_header.clone());
+            req.headers_mut().insert(USER_AGENT, rules.user_agent_header.clone());
             self.stats.inc_modified();
-            self.cache_put(&ua_owned, CacheDecision::Modify);
+            self.stats.clients().inc_modified(client_ip);
+            self.stats.top().record_ua(&ua_owned, true);
+            self.cache_put(&rules, &ua_owned, CacheDecision::Modify);
 
-            logger::log(
+            logger::log_at(
                 logger::Level::Debug,
-                format_args!("UA modified: {} -> {}", ua_owned, self.config.user_agent)
+                "handler",
+                Some(client_ip),
+                format_args!("UA modified: {} -> {}", ua_owned, rules.config.user_agent)
             );
         } else {
-            self.cache_put(&original_ua, CacheDecision::Pass);
+            logger::log_at(
+                logger::Level::Debug,
+                "handler",
+                Some(client_ip),
+                format_args!("UA passed: {}", original_ua)
+            );
+            self.stats.top().record_ua(&original_ua, false);
+            self.cache_put(&rules, &original_ua, CacheDecision::Pass);
         }
 
-        Ok(req)
+        Outcome::Forward(req)
     }
 
     /// 报告非 HTTP 流量给防火墙
-    pub fn report_non_http(&self, dest_ip: IpAddr, dest_port: u16) {
-        if self.fw.enabled() && self.config.firewall.fw_bypass {
-            self.fw.report_non_http(dest_ip, dest_port);
+    pub fn report_non_http(&self, client_ip: IpAddr, dest_ip: IpAddr, dest_port: u16) {
+        if self.fw.enabled() && self.rules.read().config.firewall.fw_bypass {
+            self.fw.report_non_http(client_ip, dest_ip, dest_port);
         }
     }
 }
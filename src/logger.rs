@@ -1,35 +1,113 @@
 use std::fs::OpenOptions;
 use std::io::{self, Write};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::OnceLock;
 use std::time::{SystemTime, UNIX_EPOCH};
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[repr(u8)]
 pub enum Level {
-    Debug,
-    Info,
-    Warn,
-    Error,
+    Debug = 0,
+    Info = 1,
+    Warn = 2,
+    Error = 3,
 }
 
 impl Level {
     pub fn parse(s: &str) -> Level {
-        match s.to_ascii_lowercase().as_str() {
-            "debug" => Level::Debug,
-            "warn" => Level::Warn,
-            "error" => Level::Error,
-            _ => Level::Info,
+        Self::try_parse(s).unwrap_or(Level::Info)
+    }
+
+    /// 严格解析，未知级别返回 None（用于运行时修改）
+    pub fn try_parse(s: &str) -> Option<Level> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "debug" => Some(Level::Debug),
+            "info" => Some(Level::Info),
+            "warn" => Some(Level::Warn),
+            "error" => Some(Level::Error),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+
+    fn from_u8(v: u8) -> Level {
+        match v {
+            0 => Level::Debug,
+            1 => Level::Info,
+            2 => Level::Warn,
+            _ => Level::Error,
         }
     }
 }
 
 struct Logger {
-    level: Level,
     out: Mutex<Box<dyn Write + Send>>,
 }
 
+// 当前日志级别，可在运行时通过管理接口或 SIGUSR1 修改
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+// SIGUSR1 切换到 debug 之前的级别，再次切换时恢复
+static SAVED_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// 可用于过滤的模块名
+pub const MODULES: &[&str] = &["server", "handler", "firewall", "admin"];
+
+/// 调试日志过滤器：只影响 debug 级别，为空表示不过滤
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub modules: Vec<String>,
+    pub clients: Vec<IpAddr>,
+}
+
+impl Filter {
+    /// 由逗号分隔的模块列表和客户端 IP 列表构造，模块名需在 `MODULES` 中
+    pub fn parse(modules: &str, clients: &str) -> Result<Self, String> {
+        let modules: Vec<String> = modules
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect();
+        if let Some(m) = modules.iter().find(|m| !MODULES.contains(&m.as_str())) {
+            return Err(format!("unknown log module: {} (expected {})", m, MODULES.join("/")));
+        }
+        let clients = clients
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<IpAddr>().map_err(|_| format!("invalid client ip: {}", s)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { modules, clients })
+    }
+
+    /// 模块与客户端条件同时满足才输出；设置了客户端过滤时，不带客户端的日志被忽略
+    fn allows(&self, module: &str, client: Option<IpAddr>) -> bool {
+        (self.modules.is_empty() || self.modules.iter().any(|m| m == module))
+            && (self.clients.is_empty() || client.is_some_and(|c| self.clients.contains(&c)))
+    }
+}
+
+static FILTER: RwLock<Filter> = RwLock::new(Filter {
+    modules: Vec::new(),
+    clients: Vec::new(),
+});
+
 static LOGGER: OnceLock<Logger> = OnceLock::new();
 
+/// 修改全局日志级别或过滤器的测试互相串行
+#[cfg(test)]
+pub static TEST_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
 pub fn init(level: Level, path: Option<&str>) -> io::Result<()> {
     let writer: Box<dyn Write + Send> = if let Some(p) = path {
         let f = OpenOptions::new().create(true).append(true).open(p)?;
@@ -37,19 +115,57 @@ pub fn init(level: Level, path: Option<&str>) -> io::Result<()> {
     } else {
         Box::new(io::stderr())
     };
+    set_level(level);
     let _ = LOGGER.set(Logger {
-        level,
         out: Mutex::new(writer),
     });
     Ok(())
 }
 
+pub fn level() -> Level {
+    Level::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// 在 debug 与之前的级别之间切换，返回切换后的级别
+pub fn toggle_debug() -> Level {
+    let current = level();
+    if current == Level::Debug {
+        let saved = Level::from_u8(SAVED_LEVEL.load(Ordering::Relaxed));
+        set_level(saved);
+        saved
+    } else {
+        SAVED_LEVEL.store(current as u8, Ordering::Relaxed);
+        set_level(Level::Debug);
+        Level::Debug
+    }
+}
+
+pub fn filter() -> Filter {
+    FILTER.read().clone()
+}
+
+pub fn set_filter(filter: Filter) {
+    *FILTER.write() = filter;
+}
+
 pub fn log(level: Level, args: std::fmt::Arguments) {
+    log_at(level, "", None, args);
+}
+
+/// 带模块名和客户端地址的日志；debug 级别会经过过滤器
+pub fn log_at(level: Level, module: &str, client: Option<IpAddr>, args: std::fmt::Arguments) {
     let Some(logger) = LOGGER.get() else {
         let _ = writeln!(io::stderr(), "{}", args);
         return;
     };
-    if level < logger.level {
+    if level < self::level() {
+        return;
+    }
+    if level == Level::Debug && !FILTER.read().allows(module, client) {
         return;
     }
     let ts = SystemTime::now()
@@ -63,7 +179,62 @@ pub fn log(level: Level, args: std::fmt::Arguments) {
         Level::Error => "ERROR",
     };
     let mut out = logger.out.lock();
-    let _ = writeln!(out, "[{ts}] [{level_str}] {}", args);
+    let _ = if module.is_empty() {
+        writeln!(out, "[{ts}] [{level_str}] {}", args)
+    } else {
+        writeln!(out, "[{ts}] [{level_str}] [{module}] {}", args)
+    };
     // 移除 flush() 以减少 I/O 阻塞，依赖操作系统缓冲
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_rejects_unknown_module() {
+        let err = Filter::parse("server,proxy", "").unwrap_err();
+        assert!(err.contains("unknown log module: proxy"), "{err}");
+        assert!(Filter::parse("", "192.168.1.300").is_err());
+
+        let filter = Filter::parse(" server , admin,", " 192.168.1.10 ,::1").unwrap();
+        assert_eq!(filter.modules, ["server", "admin"]);
+        assert_eq!(filter.clients.len(), 2);
+    }
+
+    #[test]
+    fn client_filter_drops_lines_without_client() {
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+        let filter = Filter::parse("", "192.168.1.10").unwrap();
+        assert!(filter.allows("server", ip("192.168.1.10")));
+        assert!(!filter.allows("server", ip("192.168.1.11")));
+        assert!(!filter.allows("server", None));
+        assert!(!filter.allows("", None));
+
+        let filter = Filter::parse("firewall", "192.168.1.10").unwrap();
+        assert!(filter.allows("firewall", ip("192.168.1.10")));
+        assert!(!filter.allows("server", ip("192.168.1.10")));
+
+        assert!(Filter::default().allows("", None));
+    }
+
+    #[test]
+    fn toggle_debug_restores_previous_level() {
+        let _guard = TEST_LOCK.blocking_lock();
+        let before = level();
+
+        set_level(Level::Warn);
+        assert_eq!(toggle_debug(), Level::Debug);
+        assert_eq!(level(), Level::Debug);
+        assert_eq!(toggle_debug(), Level::Warn);
+        assert_eq!(level(), Level::Warn);
+
+        // 恢复的是最近一次切换前的级别
+        set_level(Level::Error);
+        toggle_debug();
+        assert_eq!(toggle_debug(), Level::Error);
+
+        set_level(before);
+    }
+}
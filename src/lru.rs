@@ -1,5 +1,9 @@
 use lru::LruCache;
+use parking_lot::Mutex;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
 use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
 
 // Type-safe cache decisions (zero-cost enum)
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -10,25 +14,262 @@ pub enum CacheDecision {
     Pass = 2,
 }
 
+/// 缓存查询结果：过期条目在查询时删除，并单独报告以便统计
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lookup {
+    Hit(CacheDecision),
+    Miss,
+    Expired,
+}
+
+// 每个条目除 UA 原文外的估算开销：LRU 链表节点（键、值、前后指针）+ 哈希表槽位
+const ENTRY_OVERHEAD: usize = 96;
+
+struct Entry {
+    decision: CacheDecision,
+    // 开启校验时保存 UA 原文，用于排除哈希碰撞
+    ua: Option<Box<str>>,
+    // None 表示只随 LRU 淘汰
+    expires: Option<Instant>,
+}
+
+impl Entry {
+    fn cost(&self) -> usize {
+        ENTRY_OVERHEAD + self.ua.as_ref().map_or(0, |ua| ua.len())
+    }
+}
+
+struct Shard {
+    lru: LruCache<u64, Entry>,
+    bytes: usize,
+}
+
+/// 分片 LRU 缓存：按键的哈希分到多个各自加锁的 LRU，
+/// 不同连接的请求大多落在不同分片上，互不阻塞。
+/// 每个分片的容量为总容量均分（向上取整），淘汰只在分片内进行。
+///
+/// 键为 UA 的 64 位哈希（带随机种子的 SipHash，客户端无法构造碰撞），
+/// 不保存 UA 原文，每个条目占用固定大小，恶意的超长 UA 不会撑大内存。
+/// 缓存的是 UA 规则的匹配结果，只取决于 UA 本身，因此键中不含 Host 等字段；
+/// FwWhitelist 命中后仍需按目标写入卸载条目，这一步由调用方在每次命中时完成。
+/// 开启校验时额外保存 UA 原文，命中时比对，此时由字节预算约束总内存。
 pub struct Cache {
-    inner: LruCache<String, CacheDecision>,
+    shards: Box<[Mutex<Shard>]>,
+    hasher: RandomState,
+    // 每个分片的字节预算，0 表示只按条目数限制
+    shard_budget: usize,
+    verify: bool,
 }
 
 impl Cache {
-    pub fn new(cap: usize) -> Self {
+    /// shards 为 0 时按 CPU 核数自动选择；分片数向上取整为 2 的幂，且不超过容量。
+    /// max_bytes 为全部分片的估算内存上限，0 表示只按条目数限制。
+    pub fn new(cap: usize, shards: usize, max_bytes: usize, verify: bool) -> Self {
         // cap=0 表示禁用缓存，但仍需创建最小容量避免 panic
         // 实际的禁用检查在 HttpHandler 层通过 cache_enabled 快路径完成
-        let capacity = NonZeroUsize::new(cap.max(1)).unwrap();
+        let cap = cap.max(1);
+        let shards = if shards == 0 { default_shards() } else { shards };
+        let shards = shards.next_power_of_two().min(cap.next_power_of_two()).max(1);
+        let per_shard = NonZeroUsize::new(cap.div_ceil(shards)).unwrap();
         Self {
-            inner: LruCache::new(capacity),
+            shards: (0..shards)
+                .map(|_| Mutex::new(Shard { lru: LruCache::new(per_shard), bytes: 0 }))
+                .collect(),
+            hasher: RandomState::new(),
+            shard_budget: max_bytes.div_ceil(shards),
+            verify,
         }
     }
 
-    pub fn get(&mut self, key: &str) -> Option<CacheDecision> {
-        self.inner.get(key).copied()
+    pub fn get(&self, ua: &str) -> Lookup {
+        let (hash, shard) = self.locate(ua);
+        let mut shard = shard.lock();
+        let (decision, expired) = match shard.lru.get(&hash) {
+            Some(entry) if entry.ua.as_deref().is_none_or(|stored| stored == ua) => {
+                (entry.decision, entry.expires.is_some_and(|t| t <= Instant::now()))
+            }
+            _ => return Lookup::Miss,
+        };
+        if expired {
+            if let Some(old) = shard.lru.pop(&hash) {
+                shard.bytes -= old.cost();
+            }
+            return Lookup::Expired;
+        }
+        Lookup::Hit(decision)
+    }
+
+    /// ttl 为 None 时条目只随 LRU 淘汰
+    pub fn put(&self, ua: &str, decision: CacheDecision, ttl: Option<Duration>) {
+        let entry = Entry {
+            decision,
+            ua: self.verify.then(|| ua.into()),
+            expires: ttl.map(|d| Instant::now() + d),
+        };
+        let cost = entry.cost();
+        if self.shard_budget > 0 && cost > self.shard_budget {
+            return;
+        }
+
+        let (hash, shard) = self.locate(ua);
+        let mut shard = shard.lock();
+        shard.bytes += cost;
+        // push 返回被替换的同键旧值或被淘汰的最久未用条目
+        if let Some((_, old)) = shard.lru.push(hash, entry) {
+            shard.bytes -= old.cost();
+        }
+        while self.shard_budget > 0 && shard.bytes > self.shard_budget {
+            match shard.lru.pop_lru() {
+                Some((_, old)) => shard.bytes -= old.cost(),
+                None => break,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().lru.len()).sum()
+    }
+
+    /// 估算的内存占用（字节）
+    pub fn bytes(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().bytes).sum()
+    }
+
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock();
+            shard.lru.clear();
+            shard.bytes = 0;
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn locate(&self, ua: &str) -> (u64, &Mutex<Shard>) {
+        let hash = self.hasher.hash_one(ua);
+        // 分片数为 2 的幂，取哈希高位选分片，低位留给分片内的哈希表
+        let idx = (hash >> 32) as usize & (self.shards.len() - 1);
+        (hash, &self.shards[idx])
+    }
+}
+
+/// 每核 4 个分片，单核路由器上保持单分片（无额外开销）
+fn default_shards() -> usize {
+    match std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1) {
+        1 => 1,
+        n => n * 4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ua(n: usize) -> String {
+        format!("Mozilla/5.0 test-{n:04}")
+    }
+
+    #[test]
+    fn hit_and_miss() {
+        let cache = Cache::new(16, 1, 0, false);
+        assert_eq!(cache.get("a"), Lookup::Miss);
+        cache.put("a", CacheDecision::Modify, None);
+        cache.put("b", CacheDecision::Pass, None);
+        assert_eq!(cache.get("a"), Lookup::Hit(CacheDecision::Modify));
+        assert_eq!(cache.get("b"), Lookup::Hit(CacheDecision::Pass));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn expired_entry_is_reported_once() {
+        let cache = Cache::new(16, 1, 0, true);
+        cache.put("a", CacheDecision::Modify, Some(Duration::ZERO));
+        cache.put("b", CacheDecision::Modify, Some(Duration::from_secs(3600)));
+        assert_eq!(cache.get("a"), Lookup::Expired);
+        // 过期条目在查询时已删除，字节数随之扣除
+        assert_eq!(cache.get("a"), Lookup::Miss);
+        assert_eq!(cache.get("b"), Lookup::Hit(CacheDecision::Modify));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.bytes(), ENTRY_OVERHEAD + 1);
+    }
+
+    #[test]
+    fn byte_budget_evicts_least_recent() {
+        let cost = ENTRY_OVERHEAD + ua(0).len();
+        let cache = Cache::new(1000, 1, cost * 3, true);
+        for n in 0..3 {
+            cache.put(&ua(n), CacheDecision::Modify, None);
+        }
+        assert_eq!(cache.bytes(), cost * 3);
+
+        // 访问 0 使 1 成为最久未用
+        assert_eq!(cache.get(&ua(0)), Lookup::Hit(CacheDecision::Modify));
+        cache.put(&ua(3), CacheDecision::Pass, None);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.bytes(), cost * 3);
+        assert_eq!(cache.get(&ua(1)), Lookup::Miss);
+        assert_eq!(cache.get(&ua(0)), Lookup::Hit(CacheDecision::Modify));
+
+        // 覆盖同键不重复计费
+        cache.put(&ua(3), CacheDecision::Modify, None);
+        assert_eq!(cache.bytes(), cost * 3);
+    }
+
+    #[test]
+    fn oversized_entry_is_not_cached() {
+        let cache = Cache::new(16, 1, ENTRY_OVERHEAD + 8, true);
+        cache.put("short", CacheDecision::Modify, None);
+        cache.put(&"x".repeat(64), CacheDecision::Modify, None);
+        assert_eq!(cache.get(&"x".repeat(64)), Lookup::Miss);
+        assert_eq!(cache.get("short"), Lookup::Hit(CacheDecision::Modify));
+    }
+
+    #[test]
+    fn zero_budget_limits_by_count() {
+        let cache = Cache::new(4, 1, 0, false);
+        for n in 0..8 {
+            cache.put(&ua(n), CacheDecision::Pass, None);
+        }
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.bytes(), ENTRY_OVERHEAD * 4);
+    }
+
+    /// 把 `stored` 的条目写到 `ua` 的哈希槽位上，模拟两者哈希碰撞
+    fn forge_collision(cache: &Cache, ua: &str, stored: &str) {
+        let (hash, shard) = cache.locate(ua);
+        let entry = Entry {
+            decision: CacheDecision::FwWhitelist,
+            ua: Some(stored.into()),
+            expires: None,
+        };
+        shard.lock().lru.push(hash, entry);
+    }
+
+    #[test]
+    fn verify_rejects_collision() {
+        let cache = Cache::new(16, 1, 0, true);
+        forge_collision(&cache, "victim", "attacker");
+        assert_eq!(cache.get("victim"), Lookup::Miss);
+    }
+
+    #[test]
+    fn unverified_entries_trust_the_hash() {
+        let cache = Cache::new(16, 1, 0, false);
+        cache.put("a", CacheDecision::Pass, None);
+        assert_eq!(cache.bytes(), ENTRY_OVERHEAD);
+
+        // 不保存 UA 原文的条目直接按哈希命中
+        let (hash, shard) = cache.locate("b");
+        shard.lock().lru.push(hash, Entry { decision: CacheDecision::Modify, ua: None, expires: None });
+        assert_eq!(cache.get("b"), Lookup::Hit(CacheDecision::Modify));
     }
 
-    pub fn put(&mut self, key: String, value: CacheDecision) {
-        self.inner.put(key, value);
+    #[test]
+    fn shard_count_is_power_of_two_within_capacity() {
+        assert_eq!(Cache::new(100, 6, 0, false).shard_count(), 8);
+        assert_eq!(Cache::new(2, 16, 0, false).shard_count(), 2);
+        assert_eq!(Cache::new(0, 4, 0, false).shard_count(), 1);
     }
 }
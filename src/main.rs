@@ -1,15 +1,21 @@
+
+mod admin;
+mod clients;
 mod config;
 mod firewall;
 mod handler;
 mod lru;
 mod logger;
+mod outbound;
+mod proxy_protocol;
 mod server;
+mod socks5;
 mod stats;
+mod topn;
 mod tproxy;
 
 use std::process::ExitCode;
 use std::sync::Arc;
-use std::time::Duration;
 
 use config::Config;
 
@@ -30,29 +36,98 @@ async fn main() -> ExitCode {
         logger::Level::parse(&config.log_level),
         config.log_file.as_deref(),
     );
+    logger::set_filter(config.log_filter.clone());
 
     if config.show_version {
         println!("UAForge version: {VERSION}");
         return ExitCode::SUCCESS;
     }
 
-    let stats = Arc::new(stats::Stats::new());
-    stats.start_writer("/tmp/uaforge.stats", Duration::from_secs(5));
+    let stats = Arc::new(stats::Stats::new(config.top_capacity, config.client_stats_capacity));
+    stats.start_writer(
+        &config.stats_file,
+        config.stats_interval,
+        config.stats_format,
+        config.top_file.as_deref(),
+        config.client_stats_file.as_deref(),
+    );
 
-    let fw = Arc::new(firewall::FirewallManager::new(config.firewall.clone()));
-    let handler = match handler::HttpHandler::new(config.clone(), stats.clone(), fw) {
+    let fw = Arc::new(firewall::FirewallManager::new(config.firewall.clone(), stats.clone()));
+    let handler = match handler::HttpHandler::new(config.clone(), stats.clone(), fw.clone()) {
         Ok(h) => Arc::new(h),
         Err(e) => {
             eprintln!("[uaforge] handler init error: {e}");
             return ExitCode::from(2);
         }
     };
+
+    if let Some(addr) = config.admin_listen {
+        let admin = admin::AdminServer::new(
+            addr,
+            config.admin_token.clone(),
+            handler.clone(),
+            stats.clone(),
+            fw.clone(),
+        );
+        tokio::spawn(async move {
+            if let Err(e) = admin.run().await {
+                logger::log(logger::Level::Error, format_args!("admin API error: {e}"));
+            }
+        });
+    }
+
+    tokio::spawn(toggle_debug_on_signal());
+
     let server = server::Server::new(config, handler, stats);
 
-    if let Err(e) = server.run().await {
+    let result = tokio::select! {
+        r = server.run() => r,
+        _ = shutdown_signal() => {
+            logger::log(logger::Level::Info, format_args!("shutdown signal received"));
+            Ok(())
+        }
+    };
+
+    // 等待防火墙线程写出剩余批次和状态文件
+    fw.shutdown();
+
+    if let Err(e) = result {
         eprintln!("[uaforge] server error: {e}");
         return ExitCode::from(1);
     }
 
     ExitCode::SUCCESS
 }
+
+/// SIGUSR1：在 debug 与原日志级别之间切换，无需重启即可临时排查
+async fn toggle_debug_on_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let Ok(mut usr1) = signal(SignalKind::user_defined1()) else {
+        return;
+    };
+    while usr1.recv().await.is_some() {
+        let level = logger::toggle_debug();
+        logger::log(
+            logger::Level::Info,
+            format_args!("log level switched to {} by SIGUSR1", level.as_str()),
+        );
+    }
+}
+
+/// 等待 SIGTERM（procd 停止服务）或 Ctrl-C
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut term) => {
+            tokio::select! {
+                _ = term.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
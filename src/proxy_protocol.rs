@@ -0,0 +1,297 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// 头部必须在连接建立后这段时间内到达
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// v1：文本行，含结尾 \r\n 最长 107 字节
+const V1_MAX_LEN: usize = 107;
+// v2：12 字节签名 + 版本/命令 + 协议族 + 2 字节长度
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+// 地址段加 TLV 的上限，正常的 TCP4/TCP6 头远小于此
+const V2_MAX_LEN: usize = 1024;
+
+const V2_CMD_LOCAL: u8 = 0x00;
+const V2_CMD_PROXY: u8 = 0x01;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+/// PROXY 协议头携带的真实来源与目标
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+}
+
+/// 读取并消费连接开头的 PROXY 协议头（v1 或 v2）。
+///
+/// 返回 None 表示头部合法但不携带地址（v1 UNKNOWN、v2 LOCAL 或非 TCP 协议族），
+/// 调用方应退回使用连接自身的地址。缺少头部或格式错误时返回错误：
+/// 启用后头部是必需的，不能猜测后续数据是否属于应用层。
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<Header>> {
+    tokio::time::timeout(HEADER_TIMEOUT, read(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "PROXY protocol header timed out"))?
+}
+
+async fn read<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<Header>> {
+    // "PROXY" 与 v2 签名的前 5 字节足以区分两个版本，且不超过最短的 v1 头
+    let mut prefix = [0u8; 5];
+    stream.read_exact(&mut prefix).await?;
+    if &prefix == b"PROXY" {
+        read_v1(stream).await
+    } else if prefix == V2_SIGNATURE[..5] {
+        read_v2(stream).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+/// `PROXY TCP4|TCP6|UNKNOWN <src> <dst> <sport> <dport>\r\n`（前缀 "PROXY" 已读）
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<Header>> {
+    let mut line = Vec::with_capacity(64);
+    while !line.ends_with(b"\r\n") {
+        if line.len() + 5 >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        [proto @ ("TCP4" | "TCP6"), src, dst, sport, dport] => {
+            let parse_ip = |s: &str| -> io::Result<IpAddr> {
+                let ip: IpAddr = s.parse().map_err(|_| invalid("invalid PROXY v1 address"))?;
+                if ip.is_ipv4() != (*proto == "TCP4") {
+                    return Err(invalid("PROXY v1 address does not match protocol"));
+                }
+                Ok(ip)
+            };
+            let parse_port = |s: &str| s.parse::<u16>().map_err(|_| invalid("invalid PROXY v1 port"));
+            Ok(Some(Header {
+                src: SocketAddr::new(parse_ip(src)?, parse_port(sport)?),
+                dst: SocketAddr::new(parse_ip(dst)?, parse_port(dport)?),
+            }))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+/// 二进制头（签名前 5 字节已读）
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<Header>> {
+    let mut fixed = [0u8; 11];
+    stream.read_exact(&mut fixed).await?;
+    if fixed[..7] != V2_SIGNATURE[5..] {
+        return Err(invalid("invalid PROXY v2 signature"));
+    }
+    let (ver_cmd, family) = (fixed[7], fixed[8]);
+    let len = u16::from_be_bytes([fixed[9], fixed[10]]) as usize;
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    if len > V2_MAX_LEN {
+        return Err(invalid("PROXY v2 header too long"));
+    }
+
+    // 地址段之后的 TLV 一并读掉
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+
+    match ver_cmd & 0x0F {
+        V2_CMD_LOCAL => return Ok(None),
+        V2_CMD_PROXY => {}
+        cmd => return Err(invalid(&format!("unsupported PROXY v2 command: {}", cmd))),
+    }
+
+    let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+    match family {
+        V2_TCP4 if len >= 12 => {
+            let ip = |b: &[u8]| IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3]));
+            Ok(Some(Header {
+                src: SocketAddr::new(ip(&body[0..4]), port(&body[8..10])),
+                dst: SocketAddr::new(ip(&body[4..8]), port(&body[10..12])),
+            }))
+        }
+        V2_TCP6 if len >= 36 => {
+            let ip = |b: &[u8]| {
+                let octets: [u8; 16] = b.try_into().unwrap_or_default();
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            Ok(Some(Header {
+                src: SocketAddr::new(ip(&body[0..16]), port(&body[32..34])),
+                dst: SocketAddr::new(ip(&body[16..32]), port(&body[34..36])),
+            }))
+        }
+        V2_TCP4 | V2_TCP6 => Err(invalid("PROXY v2 address block too short")),
+        // UNSPEC、UDP、UNIX 等不携带可用的 TCP 地址
+        _ => Ok(None),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(mut input: &[u8]) -> (io::Result<Option<Header>>, &[u8]) {
+        let result = read_header(&mut input).await;
+        (result, input)
+    }
+
+    fn v2(cmd: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.push(0x20 | cmd);
+        buf.push(family);
+        buf.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        buf.extend_from_slice(body);
+        buf.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        buf
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let (header, rest) = parse(b"PROXY TCP4 192.168.1.10 203.0.113.1 51000 80\r\nGET /").await;
+        let header = header.unwrap().unwrap();
+        assert_eq!(header.src, addr("192.168.1.10:51000"));
+        assert_eq!(header.dst, addr("203.0.113.1:80"));
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        let (header, _) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 51000 443\r\n").await;
+        let header = header.unwrap().unwrap();
+        assert_eq!(header.src, addr("[2001:db8::1]:51000"));
+        assert_eq!(header.dst, addr("[2001:db8::2]:443"));
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() {
+        let (header, rest) = parse(b"PROXY UNKNOWN\r\nGET /").await;
+        assert!(header.unwrap().is_none());
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn v1_rejects_malformed() {
+        for input in [
+            &b"PROXY TCP4 2001:db8::1 203.0.113.1 1 2\r\n"[..],
+            b"PROXY TCP4 192.168.1.10 203.0.113.1 70000 80\r\n",
+            b"PROXY TCP4 192.168.1.10 203.0.113.1 51000\r\n",
+            b"PROXY TCP5 192.168.1.10 203.0.113.1 51000 80\r\n",
+        ] {
+            let (header, _) = parse(input).await;
+            assert_eq!(header.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_oversized() {
+        let mut line = b"PROXY TCP4 ".to_vec();
+        line.resize(200, b'1');
+        let (header, _) = parse(&line).await;
+        assert_eq!(header.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // 恰好 107 字节（含 \r\n）的头仍然合法
+        let mut line = b"PROXY UNKNOWN ".to_vec();
+        line.resize(V1_MAX_LEN - 2, b'x');
+        line.extend_from_slice(b"\r\n");
+        assert!(parse(&line).await.0.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn v2_tcp4() {
+        let body = [192, 168, 1, 10, 203, 0, 113, 1, 0xC7, 0x38, 0x00, 0x50];
+        let input = v2(V2_CMD_PROXY, V2_TCP4, &body);
+        let (header, rest) = parse(&input).await;
+        let header = header.unwrap().unwrap();
+        assert_eq!(header.src, addr("192.168.1.10:51000"));
+        assert_eq!(header.dst, addr("203.0.113.1:80"));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn v2_tcp6_with_tlv() {
+        let mut body = Vec::new();
+        body.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        body.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        body.extend_from_slice(&51000u16.to_be_bytes());
+        body.extend_from_slice(&443u16.to_be_bytes());
+        // 地址段之后的 TLV 应被一并消费
+        body.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        let input = v2(V2_CMD_PROXY, V2_TCP6, &body);
+        let (header, rest) = parse(&input).await;
+        let header = header.unwrap().unwrap();
+        assert_eq!(header.src, addr("[2001:db8::1]:51000"));
+        assert_eq!(header.dst, addr("[2001:db8::2]:443"));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn v2_local_and_unspec() {
+        let input = v2(V2_CMD_LOCAL, 0x00, &[]);
+        let (header, rest) = parse(&input).await;
+        assert!(header.unwrap().is_none());
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        // UDP 等协议族不携带可用地址
+        let input = v2(V2_CMD_PROXY, 0x12, &[0; 12]);
+        assert!(parse(&input).await.0.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn v2_rejects_bad_headers() {
+        // 地址段过短
+        let input = v2(V2_CMD_PROXY, V2_TCP4, &[0; 8]);
+        assert_eq!(parse(&input).await.0.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // 长度超过上限
+        let mut input = v2(V2_CMD_PROXY, V2_TCP4, &[]);
+        input[14..16].copy_from_slice(&(V2_MAX_LEN as u16 + 1).to_be_bytes());
+        assert_eq!(parse(&input).await.0.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // 未知命令
+        let input = v2(0x0F, V2_TCP4, &[0; 12]);
+        assert_eq!(parse(&input).await.0.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // 签名后半段不符
+        let mut input = v2(V2_CMD_PROXY, V2_TCP4, &[0; 12]);
+        input[8] = 0;
+        assert_eq!(parse(&input).await.0.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn truncated_headers() {
+        let v2_full = v2(V2_CMD_PROXY, V2_TCP4, &[0; 12]);
+        for input in [
+            &b"PROXY TCP4 192.168.1.10"[..],
+            &v2_full[..10],
+            &v2_full[..20],
+            b"PRO",
+        ] {
+            let (header, _) = parse(input).await;
+            assert_eq!(header.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_header() {
+        let (header, _) = parse(b"GET / HTTP/1.1\r\n").await;
+        assert_eq!(header.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,28 +1,49 @@
 use std::io;
 use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, RawFd};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Semaphore;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::Request;
-use hyper::body::Incoming;
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::{Method, Request, Response, StatusCode};
+use hyper::header::{
+    HeaderMap, HeaderValue, CONNECTION, CONTENT_TYPE, HOST, LOCATION, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION,
+    RETRY_AFTER, UPGRADE,
+};
+use hyper::body::{Body, Frame, Incoming, SizeHint};
 use hyper_util::rt::TokioIo;
 
-use crate::config::Config;
-use crate::handler::HttpHandler;
+use crate::config::{Config, DropAction, ProxyMode};
+use crate::handler::{HttpHandler, Outcome};
 use crate::stats::Stats;
 use crate::logger;
+use crate::outbound::{self, Cidr, Outbound};
+use crate::proxy_protocol;
+use crate::socks5;
 use crate::tproxy;
 
 // 常量定义
 const MAX_CONCURRENT_CONNECTIONS: usize = 10000;
 const PEEK_BUFFER_SIZE: usize = 8;
+// 累计到这么多字节再写入 Stats，避免每次读写都加锁
+const BYTES_FLUSH_THRESHOLD: u64 = 64 * 1024;
+
+// 返回给客户端的响应体：上游响应或代理自己生成的短文本
+type ProxyBody = BoxBody<Bytes, hyper::Error>;
 
 pub struct Server {
     config: Config,
     handler: Arc<HttpHandler>,
     stats: Arc<Stats>,
+    outbound: Arc<Outbound>,
     conn_limit: Arc<Semaphore>,
 }
 
@@ -30,10 +51,20 @@ impl Server {
     pub fn new(config: Config, handler: Arc<HttpHandler>, stats: Arc<Stats>) -> Self {
         // 限制最大并发连接数，防止 DoS 资源耗尽
         let conn_limit = Arc::new(Semaphore::new(MAX_CONCURRENT_CONNECTIONS));
+        let outbound = Arc::new(Outbound::new(
+            config.upstream.clone(),
+            config.upstream_direct.clone(),
+            config.upstream_socket.clone(),
+            std::iter::once(SocketAddr::from(([0, 0, 0, 0], config.port)))
+                .chain(config.socks5_listen)
+                .chain(config.admin_listen)
+                .collect(),
+        ));
         Self {
             config,
             handler,
             stats,
+            outbound,
             conn_limit,
         }
     }
@@ -42,13 +73,47 @@ impl Server {
         let addr = SocketAddr::from(([0, 0, 0, 0], self.config.port));
         let listener = TcpListener::bind(addr).await?;
 
+        let mode = self.config.mode;
+        logger::log(
+            logger::Level::Info,
+            format_args!(
+                "listening on {} ({} mode)",
+                addr,
+                if mode == ProxyMode::Forward { "forward proxy" } else { "transparent" }
+            ),
+        );
+        let inbound = match mode {
+            ProxyMode::Transparent => Inbound::Transparent {
+                proxy_protocol: self.config.proxy_protocol.clone().map(Arc::from),
+            },
+            ProxyMode::Forward => Inbound::Forward(Arc::new(ForwardAccess::new(&self.config))),
+        };
+
+        let Some(socks5_addr) = self.config.socks5_listen else {
+            return self.accept_loop(listener, inbound).await;
+        };
+        let socks5_listener = TcpListener::bind(socks5_addr).await?;
         logger::log(
             logger::Level::Info,
-            format_args!("listening on {} (async mode)", addr),
+            format_args!(
+                "SOCKS5 listening on {}{}",
+                socks5_addr,
+                if self.config.socks5_auth.is_some() { " (username/password)" } else { "" }
+            ),
         );
+        let socks5 = Inbound::Socks5(self.config.socks5_auth.clone().map(Arc::new));
 
+        // 两个监听共享并发连接上限
+        tokio::try_join!(
+            self.accept_loop(listener, inbound),
+            self.accept_loop(socks5_listener, socks5),
+        )?;
+        Ok(())
+    }
+
+    async fn accept_loop(&self, listener: TcpListener, inbound: Inbound) -> io::Result<()> {
         loop {
-            let (stream, _) = listener.accept().await?;
+            let (stream, peer) = listener.accept().await?;
 
             // 获取 permit，限制并发连接数
             let permit = match self.conn_limit.clone().acquire_owned().await {
@@ -62,13 +127,17 @@ impl Server {
 
             let handler = self.handler.clone();
             let stats = self.stats.clone();
+            let outbound = self.outbound.clone();
+            let inbound = inbound.clone();
 
             // 为每个连接生成一个异步任务
             tokio::spawn(async move {
                 let _permit = permit; // 持有 permit 直到连接结束
-                if let Err(e) = handle_connection(stream, handler, stats).await {
-                    logger::log(
+                if let Err(e) = handle_connection(stream, peer, inbound, handler, stats, outbound).await {
+                    logger::log_at(
                         logger::Level::Debug,
+                        "server",
+                        Some(peer.ip()),
                         format_args!("connection error: {:?}", e)
                     );
                 }
@@ -77,22 +146,100 @@ impl Server {
     }
 }
 
+/// 入站连接的类型，决定如何得到目标地址
+#[derive(Clone)]
+enum Inbound {
+    /// 防火墙重定向，目标取自 SO_ORIGINAL_DST；
+    /// 启用 PROXY 协议时，来自可信网段的连接改用头部携带的来源和目标
+    Transparent { proxy_protocol: Option<Arc<[Cidr]>> },
+    /// 显式 HTTP 代理，目标取自每个请求
+    Forward(Arc<ForwardAccess>),
+    /// SOCKS5 CONNECT，目标取自握手请求
+    Socks5(Option<Arc<socks5::Auth>>),
+}
+
 /// 处理单个连接
 async fn handle_connection(
     mut client: TcpStream,
+    mut peer: SocketAddr,
+    inbound: Inbound,
     handler: Arc<HttpHandler>,
     stats: Arc<Stats>,
+    outbound: Arc<Outbound>,
 ) -> Result<(), std::io::Error> {
+    // 出站连接在建立之后才登记本地地址。客户端总是先发数据，等到可读时
+    // 若对端是本进程的出站连接，它必然已经登记
+    client.readable().await?;
+    if outbound.is_own_connection(peer) {
+        return Err(refuse_loop(&stats, peer.ip(), format_args!("{} is our own outbound connection", peer)));
+    }
+
+    // PROXY 协议头携带真实的客户端，需在按客户端统计之前解析
+    let mut proxied_dest = None;
+    if let Inbound::Transparent { proxy_protocol: Some(trusted) } = &inbound {
+        if trusted.iter().any(|c| c.contains(peer.ip())) {
+            if let Some(header) = proxy_protocol::read_header(&mut client).await? {
+                logger::log_at(
+                    logger::Level::Debug,
+                    "server",
+                    Some(header.src.ip()),
+                    format_args!("PROXY header from {}: {} -> {}", peer, header.src, header.dst)
+                );
+                peer = header.src;
+                proxied_dest = Some(header.dst);
+            }
+        }
+    }
+
     stats.inc_active();
-    let _guard = scopeguard::guard((), |_| stats.dec_active());
+    stats.clients().connect(peer.ip());
+    let _guard = scopeguard::guard((), |_| {
+        stats.dec_active();
+        stats.clients().disconnect(peer.ip());
+    });
+    stats.top().record_client(peer.ip());
 
     // 获取原始目标地址
-    let orig_dst = tproxy::original_dst_tokio(&client)?;
-    let dest_ip = std::net::IpAddr::V4(*orig_dst.ip());
-    let dest_port = orig_dst.port();
+    let local = client.local_addr()?;
+    let dest = match inbound {
+        Inbound::Transparent { .. } => {
+            let dest = match proxied_dest {
+                Some(dest) => dest,
+                None => SocketAddr::V4(tproxy::original_dst_tokio(&client)?),
+            };
+            if outbound.is_listen_addr(dest, local) {
+                return Err(refuse_loop(&stats, peer.ip(), format_args!("{} -> {} is a local listen address", peer, dest)));
+            }
+            dest
+        }
+        Inbound::Socks5(auth) => {
+            let permit = |dest| refuse_target(&outbound, &stats, peer.ip(), dest, local).is_none();
+            socks5::handshake(&mut client, auth.as_deref(), permit).await?
+        }
+        Inbound::Forward(access) => {
+            return handle_forward_connection(client, peer, access, handler, stats.clone(), outbound).await;
+        }
+    };
+
+    proxy_stream(client, peer, dest, handler, stats.clone(), outbound).await
+}
+
+/// 目标已知的连接：检测是否为 HTTP，HTTP 交给 hyper 改写，其余直接转发
+async fn proxy_stream(
+    client: TcpStream,
+    peer: SocketAddr,
+    dest: SocketAddr,
+    handler: Arc<HttpHandler>,
+    stats: Arc<Stats>,
+    outbound: Arc<Outbound>,
+) -> Result<(), std::io::Error> {
+    let dest_ip = dest.ip();
+    let dest_port = dest.port();
 
-    logger::log(
+    logger::log_at(
         logger::Level::Debug,
+        "server",
+        Some(peer.ip()),
         format_args!("connection to {}:{}", dest_ip, dest_port)
     );
 
@@ -104,77 +251,567 @@ async fn handle_connection(
 
     if !is_http {
         // 非 HTTP 流量，报告给防火墙并直接转发
-        handler.report_non_http(dest_ip, dest_port);
-
-        logger::log(
-            logger::Level::Debug,
-            format_args!("non-HTTP traffic to {}:{}, bypassing", dest_ip, dest_port)
-        );
+        note_non_http(&handler, &stats, peer.ip(), dest);
 
         // 连接到真实服务器并直接转发
-        let mut server = TcpStream::connect(orig_dst).await?;
+        let mut server = outbound.connect(dest)
+            .await
+            .inspect_err(|_| stats.inc_upstream_connect_failures())?;
+        let mut client = CountingIo::new(client, peer.ip(), stats.clone());
         tokio::io::copy_bidirectional(&mut client, &mut server).await?;
         return Ok(());
     }
 
-    // HTTP 流量，使用 hyper 处理
-    process_http(client, handler, dest_ip, dest_port).await
+    // HTTP 流量，使用 hyper 处理；fw_drop 的 RST 动作需要直接设置客户端 socket
+    let client_fd = client.as_raw_fd();
+    let client = CountingIo::new(client, peer.ip(), stats.clone());
+    process_http(client, client_fd, handler, stats, outbound, peer.ip(), dest).await
 }
 
-/// 使用 hyper 处理 HTTP 请求
-async fn process_http(
-    client: TcpStream,
+/// 非 HTTP 流量：报告给防火墙并计数，随后由调用方原样转发
+fn note_non_http(handler: &HttpHandler, stats: &Stats, client_ip: std::net::IpAddr, dest: SocketAddr) {
+    handler.report_non_http(client_ip, dest.ip(), dest.port());
+
+    logger::log_at(
+        logger::Level::Debug,
+        "server",
+        Some(client_ip),
+        format_args!("non-HTTP traffic to {}, bypassing", dest)
+    );
+
+    stats.inc_non_http();
+    stats.clients().inc_non_http(client_ip);
+}
+
+/// 使用 hyper 处理 HTTP 请求。client 已计入字节统计（CountingIo 或其上的升级连接）
+async fn process_http<S>(
+    client: S,
+    client_fd: RawFd,
     handler: Arc<HttpHandler>,
-    dest_ip: std::net::IpAddr,
-    dest_port: u16,
-) -> Result<(), std::io::Error> {
+    stats: Arc<Stats>,
+    outbound: Arc<Outbound>,
+    client_ip: std::net::IpAddr,
+    dest_addr: SocketAddr,
+) -> Result<(), std::io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     // 使用 TokioIo 包装客户端连接
     let client_io = TokioIo::new(client);
 
-    // 创建目标地址
-    let dest_addr = std::net::SocketAddr::new(dest_ip, dest_port);
+    let upstream_stats = stats.clone();
+    let service = service_fn(move |req: Request<Incoming>| {
+        forward_request(handler.clone(), upstream_stats.clone(), outbound.clone(), req, client_ip, client_fd, dest_addr)
+    });
+
+    serve_http(client_io, service, &stats).await
+}
+
+/// 以 HTTP/1 服务客户端连接（支持协议升级），解析错误计入统计
+async fn serve_http<I, S>(
+    client_io: TokioIo<I>,
+    service: S,
+    stats: &Stats,
+) -> Result<(), std::io::Error>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: hyper::service::HttpService<Incoming, ResBody = ProxyBody>,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    // 记录请求头的原始大小写（存入请求扩展），转发给上游时原样写出。
+    // HeaderMap 按插入顺序迭代，即线上顺序；UA 改写原位替换值，删除头时需整体重建（见 strip_headers）。
+    // 已知限制：重复出现的同名头会被归并到第一次出现的位置，与其他头的相对顺序不保留。
+    http1::Builder::new()
+        .preserve_header_case(true)
+        .serve_connection(client_io, service)
+        .with_upgrades()
+        .await
+        .inspect_err(|e| {
+            if e.is_parse() {
+                stats.inc_http_parse_errors();
+            }
+        })
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    Ok(())
+}
+
+/// 改写请求并转发到上游，返回上游的响应
+async fn forward_request(
+    handler: Arc<HttpHandler>,
+    stats: Arc<Stats>,
+    outbound: Arc<Outbound>,
+    req: Request<Incoming>,
+    client_ip: std::net::IpAddr,
+    client_fd: RawFd,
+    dest_addr: SocketAddr,
+) -> Result<Response<ProxyBody>, std::io::Error> {
+    let started = Instant::now();
+
+    // 修改请求
+    let (mut modified_req, close_after) = match handler.modify_request(req, client_ip, dest_addr.ip(), dest_addr.port()).await {
+        Outcome::Forward(req) => (req, false),
+        Outcome::Drop(DropAction::Pass, req) => (req, true),
+        Outcome::Drop(action, req) => return drop_connection(action, &req, client_fd),
+    };
+    stats.record_modify(started.elapsed());
+
+    // 协议升级（WebSocket 等）：握手请求照常改写 UA，上游返回 101 后两端直接拼接
+    let client_upgrade = is_upgrade_request(modified_req.headers())
+        .then(|| hyper::upgrade::on(&mut modified_req));
+
+    // 直接创建新连接（每请求新建，确保 HTTP/1.1 协议正确性）
+    let connect_started = Instant::now();
+    let stream = match outbound.connect(dest_addr).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            stats.inc_upstream_connect_failures();
+            return Ok(upstream_error(&handler, connect_error_status(&e), client_ip, dest_addr, &e));
+        }
+    };
+    stats.record_connect(connect_started.elapsed());
+    let io = TokioIo::new(stream);
+
+    // 按请求扩展中记录的原始大小写写出请求头，上游响应头的大小写同样原样返回给客户端
+    let handshake = hyper::client::conn::http1::Builder::new()
+        .preserve_header_case(true)
+        .handshake(io)
+        .await;
+    let (mut sender, conn) = match handshake {
+        Ok(handshake) => handshake,
+        Err(e) => return Ok(upstream_error(&handler, StatusCode::BAD_GATEWAY, client_ip, dest_addr, &e)),
+    };
+
+    // 在后台运行连接，保留升级能力，101 之后连接交给升级后的隧道
+    tokio::spawn(async move {
+        let _ = conn.with_upgrades().await;
+    });
+
+    // 转发请求到真实服务器
+    let send_started = Instant::now();
+    let send = sender.send_request(modified_req);
+    let result = match handler.upstream_timeout() {
+        Some(limit) => tokio::time::timeout(limit, send).await,
+        None => Ok(send.await),
+    };
+    let mut response = match result {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => return Ok(upstream_error(&handler, StatusCode::BAD_GATEWAY, client_ip, dest_addr, &e)),
+        Err(e) => return Ok(upstream_error(&handler, StatusCode::GATEWAY_TIMEOUT, client_ip, dest_addr, &e)),
+    };
+    stats.record_ttfb(send_started.elapsed());
+
+    if close_after && response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        response.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+
+    if let Some(client_upgrade) = client_upgrade {
+        if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+            let upstream_upgrade = hyper::upgrade::on(&mut response);
+            tokio::spawn(tunnel(client_upgrade, upstream_upgrade, stats.clone(), client_ip, dest_addr));
+        }
+    }
+
+    // 整个请求耗时在响应体发送完毕（或连接中断）时记录
+    Ok(response.map(|body| TimedBody::new(body, started, stats).boxed()))
+}
+
+/// 显式代理的访问控制：监听在所有地址上，不能成为任何人可用的开放代理
+struct ForwardAccess {
+    /// 允许的来源网段，其余来源返回 403
+    allow: Vec<Cidr>,
+    /// 期望的 Basic 凭据（base64 部分），未携带或不符时返回 407
+    credentials: Option<String>,
+}
+
+impl ForwardAccess {
+    fn new(config: &Config) -> Self {
+        Self {
+            allow: config.forward_allow.clone(),
+            credentials: config.forward_auth.as_ref().map(outbound::basic_credentials),
+        }
+    }
+
+    /// 不允许时返回应答给客户端的拒绝响应
+    fn check<B>(&self, req: &Request<B>, client_ip: std::net::IpAddr) -> Option<Response<ProxyBody>> {
+        if !self.allow.iter().any(|c| c.contains(client_ip)) {
+            logger::log_at(
+                logger::Level::Warn,
+                "server",
+                Some(client_ip),
+                format_args!("refusing forward proxy request from {}: source not allowed", client_ip),
+            );
+            return Some(closing_response(StatusCode::FORBIDDEN, "403 Forbidden\n"));
+        }
+        let expected = self.credentials.as_deref()?;
+        let authorized = req
+            .headers()
+            .get(PROXY_AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split_once(' '))
+            .is_some_and(|(scheme, creds)| scheme.eq_ignore_ascii_case("basic") && creds.trim() == expected);
+        if authorized {
+            return None;
+        }
+        logger::log_at(
+            logger::Level::Debug,
+            "server",
+            Some(client_ip),
+            format_args!("forward proxy request from {} without valid credentials", client_ip),
+        );
+        // 保持连接，客户端带上凭据后在同一连接上重试
+        let mut resp = text_response(StatusCode::PROXY_AUTHENTICATION_REQUIRED, "407 Proxy Authentication Required\n");
+        resp.headers_mut()
+            .insert(PROXY_AUTHENTICATE, HeaderValue::from_static("Basic realm=\"uaforge\""));
+        Some(resp)
+    }
+}
 
+/// 显式代理模式下处理单个客户端连接：目标来自请求本身，而不是 SO_ORIGINAL_DST
+async fn handle_forward_connection(
+    client: TcpStream,
+    peer: SocketAddr,
+    access: Arc<ForwardAccess>,
+    handler: Arc<HttpHandler>,
+    stats: Arc<Stats>,
+    outbound: Arc<Outbound>,
+) -> Result<(), std::io::Error> {
+    let local = client.local_addr()?;
+    let client_fd = client.as_raw_fd();
+    let client_io = TokioIo::new(CountingIo::new(client, peer.ip(), stats.clone()));
+    let upstream_stats = stats.clone();
     let service = service_fn(move |req: Request<Incoming>| {
-        let handler = handler.clone();
+        let denied = access.check(&req, peer.ip());
+        let (handler, stats, outbound) = (handler.clone(), upstream_stats.clone(), outbound.clone());
         async move {
-            // 修改请求
-            let modified_req = match handler.modify_request(req, dest_ip, dest_port).await {
-                Ok(r) => r,
-                Err(e) => {
-                    return Err(std::io::Error::new(std::io::ErrorKind::Other, e.to_string()));
-                }
-            };
+            match denied {
+                Some(resp) => Ok(resp),
+                None => forward_proxy_request(handler, stats, outbound, req, peer.ip(), client_fd, local).await,
+            }
+        }
+    });
 
-            // 直接创建新连接（每请求新建，确保 HTTP/1.1 协议正确性）
-            let stream = TcpStream::connect(dest_addr).await?;
-            let io = TokioIo::new(stream);
+    serve_http(client_io, service, &stats).await
+}
 
-            let (mut sender, conn) = hyper::client::conn::http1::handshake(io)
-                .await
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+/// 显式代理请求：CONNECT 建立隧道，绝对 URI 转为普通请求后按透明模式同样改写转发
+async fn forward_proxy_request(
+    handler: Arc<HttpHandler>,
+    stats: Arc<Stats>,
+    outbound: Arc<Outbound>,
+    mut req: Request<Incoming>,
+    client_ip: std::net::IpAddr,
+    client_fd: RawFd,
+    local: SocketAddr,
+) -> Result<Response<ProxyBody>, std::io::Error> {
+    if req.method() == Method::CONNECT {
+        let Some((host, port)) = req.uri().authority().map(|a| (a.host().to_string(), a.port_u16())) else {
+            return Ok(text_response(StatusCode::BAD_REQUEST, "CONNECT requires host:port\n"));
+        };
+        let Some(port) = port else {
+            return Ok(text_response(StatusCode::BAD_REQUEST, "CONNECT requires host:port\n"));
+        };
+        let dest_addr = match resolve(&host, port).await {
+            Ok(addr) => addr,
+            Err(e) => {
+                let target = format_args!("{}:{}", host, port);
+                return Ok(upstream_error(&handler, StatusCode::BAD_GATEWAY, client_ip, target, &e));
+            }
+        };
+        if let Some(status) = refuse_target(&outbound, &stats, client_ip, dest_addr, local) {
+            return Ok(closing_response(status, format!("{}\n", status)));
+        }
+        let upstream = match outbound.connect(dest_addr).await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                stats.inc_upstream_connect_failures();
+                return Ok(upstream_error(&handler, connect_error_status(&e), client_ip, dest_addr, &e));
+            }
+        };
 
-            // 在后台运行连接
-            tokio::spawn(async move {
-                let _ = conn.await;
-            });
+        // 隧道内与透明代理同样检测：明文 HTTP 照常改写 UA，其余原样转发
+        let client_upgrade = hyper::upgrade::on(&mut req);
+        tokio::spawn(async move {
+            let result = async {
+                let (client, is_http) = accept_tunnel(client_upgrade).await?;
+                stats.inc_connect_tunnels();
+                logger::log_at(
+                    logger::Level::Debug,
+                    "server",
+                    Some(client_ip),
+                    format_args!("CONNECT tunnel to {} ({})", dest_addr, if is_http { "http" } else { "raw" }),
+                );
+                if is_http {
+                    // 按请求逐个连接上游，预先建立的连接不再需要
+                    drop(upstream);
+                    return process_http(client, client_fd, handler, stats.clone(), outbound, client_ip, dest_addr).await;
+                }
+                note_non_http(&handler, &stats, client_ip, dest_addr);
+                let mut client = client;
+                let mut upstream = upstream;
+                tokio::io::copy_bidirectional(&mut client, &mut upstream).await.map(|_| ())
+            }
+            .await;
 
-            // 转发请求到真实服务器
-            let response = sender.send_request(modified_req)
-                .await
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+            if let Err(e) = result {
+                logger::log_at(
+                    logger::Level::Debug,
+                    "server",
+                    Some(client_ip),
+                    format_args!("CONNECT tunnel to {} failed: {}", dest_addr, e),
+                );
+            }
+        });
+        return Ok(text_response(StatusCode::OK, ""));
+    }
 
-            Ok::<_, std::io::Error>(response)
+    // 显式代理只接受 http:// 绝对 URI，https 需要客户端使用 CONNECT
+    let (host, port) = match (req.uri().scheme_str(), req.uri().authority()) {
+        (Some("http"), Some(authority)) => {
+            (authority.host().to_string(), authority.port_u16().unwrap_or(80))
         }
-    });
+        _ => {
+            return Ok(text_response(
+                StatusCode::BAD_REQUEST,
+                "expected an absolute http:// URI or CONNECT\n",
+            ));
+        }
+    };
+    let dest_addr = match resolve(&host, port).await {
+        Ok(addr) => addr,
+        Err(e) => {
+            let target = format_args!("{}:{}", host, port);
+            return Ok(upstream_error(&handler, StatusCode::BAD_GATEWAY, client_ip, target, &e));
+        }
+    };
+    if let Some(status) = refuse_target(&outbound, &stats, client_ip, dest_addr, local) {
+        return Ok(closing_response(status, format!("{}\n", status)));
+    }
 
-    http1::Builder::new()
-        .serve_connection(client_io, service)
-        .await
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    to_origin_form(&mut req)?;
+    forward_request(handler, stats, outbound, req, client_ip, client_fd, dest_addr).await
+}
 
+/// 绝对 URI 转为 origin-form 发给上游，补齐 Host，去掉只对代理有意义的头
+fn to_origin_form<B>(req: &mut Request<B>) -> Result<(), std::io::Error> {
+    if !req.headers().contains_key(HOST) {
+        if let Some(host) = req.uri().authority().and_then(|a| HeaderValue::from_str(a.as_str()).ok()) {
+            req.headers_mut().insert(HOST, host);
+        }
+    }
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    *req.uri_mut() = path
+        .parse()
+        .map_err(|e: hyper::http::uri::InvalidUri| std::io::Error::other(e.to_string()))?;
+    strip_headers(req.headers_mut(), &["proxy-connection", PROXY_AUTHORIZATION.as_str()]);
     Ok(())
 }
 
+/// 删除指定的头，其余头保持原有顺序。
+/// HeaderMap::remove 以 swap_remove 实现，会把最后一个头换到被删除的位置，因此整体重建
+fn strip_headers(headers: &mut HeaderMap, names: &[&str]) {
+    if !names.iter().any(|n| headers.contains_key(*n)) {
+        return;
+    }
+    let mut current = None;
+    for (name, value) in std::mem::take(headers) {
+        // 同名的后续值 name 为 None，沿用上一个名称
+        if let Some(name) = name {
+            current = (!names.contains(&name.as_str())).then_some(name);
+        }
+        if let Some(name) = &current {
+            headers.append(name.clone(), value);
+        }
+    }
+}
+
+/// 等待 CONNECT 升级完成，读取客户端的首批数据判断隧道内是否为 HTTP。
+/// 升级后的连接不能 peek，读出的数据由 Rewind 重新交给后续的处理
+async fn accept_tunnel(
+    client: hyper::upgrade::OnUpgrade,
+) -> Result<(Rewind<TokioIo<hyper::upgrade::Upgraded>>, bool), std::io::Error> {
+    let mut client = TokioIo::new(client.await.map_err(|e| std::io::Error::other(e.to_string()))?);
+    let mut head = vec![0u8; PEEK_BUFFER_SIZE];
+    let n = client.read(&mut head).await?;
+    head.truncate(n);
+    let is_http = is_http_request(&head);
+    Ok((Rewind::new(head.into(), client), is_http))
+}
+
+/// 解析显式代理的目标地址，IPv6 字面量带方括号
+async fn resolve(host: &str, port: u16) -> Result<SocketAddr, std::io::Error> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| std::io::Error::other(format!("no address for {}", host)))
+}
+
+/// 检查客户端自选的目标（显式代理、SOCKS5），不允许时记录日志并返回拒绝的状态码
+/// （SOCKS5 统一应答“规则不允许”）。除连回自身外，也拒绝本机回环和通配地址
+fn refuse_target(
+    outbound: &Outbound,
+    stats: &Stats,
+    client_ip: std::net::IpAddr,
+    dest: SocketAddr,
+    local: SocketAddr,
+) -> Option<StatusCode> {
+    if outbound.is_listen_addr(dest, local) {
+        refuse_loop(stats, client_ip, format_args!("{} -> {} is a local listen address", client_ip, dest));
+        return Some(StatusCode::LOOP_DETECTED);
+    }
+    if outbound.is_local_target(dest) {
+        logger::log_at(
+            logger::Level::Warn,
+            "server",
+            Some(client_ip),
+            format_args!("refusing proxy request from {} to local address {}", client_ip, dest),
+        );
+        return Some(StatusCode::FORBIDDEN);
+    }
+    None
+}
+
+/// 连回自身的流量：通常是防火墙规则没有豁免本进程的出站连接，或客户端直接连接了代理端口。
+/// 放行会递归连接自身直到耗尽并发上限，因此拒绝并记录错误日志
+fn refuse_loop(stats: &Stats, client_ip: std::net::IpAddr, what: std::fmt::Arguments) -> std::io::Error {
+    stats.inc_loops_refused();
+    logger::log_at(
+        logger::Level::Error,
+        "server",
+        Some(client_ip),
+        format_args!("refusing looped connection: {}, check firewall bypass rules", what),
+    );
+    std::io::Error::other(format!("looped connection: {}", what))
+}
+
+/// 上游连接或请求失败：返回 502/504 而不是直接断开客户端连接。
+/// 此时请求体可能未读完，响应后关闭连接
+fn upstream_error(
+    handler: &HttpHandler,
+    status: StatusCode,
+    client_ip: std::net::IpAddr,
+    target: impl std::fmt::Display,
+    err: &dyn std::fmt::Display,
+) -> Response<ProxyBody> {
+    logger::log_at(
+        logger::Level::Debug,
+        "server",
+        Some(client_ip),
+        format_args!("upstream {} failed: {}, responding {}", target, err, status),
+    );
+    let body = handler.error_body().unwrap_or_else(|| format!("{}\n", status));
+    closing_response(status, body)
+}
+
+/// fw_drop：按动作结束当前连接，客户端重连后由防火墙绕过代理
+fn drop_connection(
+    action: DropAction,
+    req: &Request<Incoming>,
+    client_fd: RawFd,
+) -> Result<Response<ProxyBody>, std::io::Error> {
+    // 无 Host 时无法拼出原 URL，退回 503
+    let location = req
+        .headers()
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|host| {
+            let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+            HeaderValue::from_str(&format!("http://{}{}", host, path)).ok()
+        });
+    match (action, location) {
+        (DropAction::Rst, _) => {
+            // 返回错误后 hyper 关闭连接，此时发出 RST
+            tproxy::set_zero_linger(&client_fd)?;
+            Err(std::io::Error::other("connection reset by fw_drop"))
+        }
+        (DropAction::Redirect, Some(location)) => {
+            let mut resp = closing_response(StatusCode::TEMPORARY_REDIRECT, "");
+            resp.headers_mut().insert(LOCATION, location);
+            Ok(resp)
+        }
+        _ => {
+            let mut resp = closing_response(StatusCode::SERVICE_UNAVAILABLE, "503 Service Unavailable\n");
+            resp.headers_mut().insert(RETRY_AFTER, HeaderValue::from_static("1"));
+            Ok(resp)
+        }
+    }
+}
+
+/// 代理自己生成的短文本响应，发送后关闭连接
+fn closing_response(status: StatusCode, body: impl Into<Bytes>) -> Response<ProxyBody> {
+    let mut resp = Response::new(Full::new(body.into()).map_err(|never| match never {}).boxed());
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
+    resp.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+    resp
+}
+
+/// 连接超时（含上游代理握手超时）为 504，其余连接失败为 502
+fn connect_error_status(err: &std::io::Error) -> StatusCode {
+    if err.kind() == std::io::ErrorKind::TimedOut {
+        StatusCode::GATEWAY_TIMEOUT
+    } else {
+        StatusCode::BAD_GATEWAY
+    }
+}
+
+fn text_response(status: StatusCode, text: &'static str) -> Response<ProxyBody> {
+    let mut resp = Response::new(
+        Full::new(Bytes::from_static(text.as_bytes()))
+            .map_err(|never| match never {})
+            .boxed(),
+    );
+    *resp.status_mut() = status;
+    resp
+}
+
+/// `Connection` 含 `upgrade` 且带 `Upgrade` 头的请求
+fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    headers.contains_key(UPGRADE)
+        && headers
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+/// 等待两端完成升级，然后双向转发原始字节直到任一端关闭。
+/// 客户端一侧仍是 CountingIo，隧道流量照常计入字节统计。
+async fn tunnel(
+    client: hyper::upgrade::OnUpgrade,
+    upstream: hyper::upgrade::OnUpgrade,
+    stats: Arc<Stats>,
+    client_ip: std::net::IpAddr,
+    dest_addr: SocketAddr,
+) {
+    let result = async {
+        let (client, upstream) = tokio::try_join!(client, upstream)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        stats.inc_upgrades();
+        logger::log_at(
+            logger::Level::Debug,
+            "server",
+            Some(client_ip),
+            format_args!("upgraded connection to {}, tunneling", dest_addr),
+        );
+        let mut client = TokioIo::new(client);
+        let mut upstream = TokioIo::new(upstream);
+        tokio::io::copy_bidirectional(&mut client, &mut upstream).await
+    }
+    .await;
+
+    if let Err(e) = result {
+        logger::log_at(
+            logger::Level::Debug,
+            "server",
+            Some(client_ip),
+            format_args!("upgrade tunnel to {} failed: {}", dest_addr, e),
+        );
+    }
+}
+
 /// 检测是否是 HTTP 请求
 fn is_http_request(buf: &[u8]) -> bool {
     const HTTP_METHODS: &[&[u8]] = &[
@@ -184,3 +821,639 @@ fn is_http_request(buf: &[u8]) -> bool {
     
     HTTP_METHODS.iter().any(|method| buf.starts_with(method))
 }
+
+/// 先读出已消费的首批数据，再继续读底层连接；写入直接透传
+struct Rewind<T> {
+    head: Bytes,
+    inner: T,
+}
+
+impl<T> Rewind<T> {
+    fn new(head: Bytes, inner: T) -> Self {
+        Self { head, inner }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Rewind<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.head.is_empty() {
+            let n = this.head.len().min(buf.remaining());
+            buf.put_slice(&this.head.split_to(n));
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Rewind<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// 统计客户端连接的转发字节数：读 = 客户端 -> 上游，写 = 上游 -> 客户端
+struct CountingIo<T> {
+    inner: T,
+    client: std::net::IpAddr,
+    stats: Arc<Stats>,
+    up: u64,
+    down: u64,
+}
+
+impl<T> CountingIo<T> {
+    fn new(inner: T, client: std::net::IpAddr, stats: Arc<Stats>) -> Self {
+        Self { inner, client, stats, up: 0, down: 0 }
+    }
+
+    fn maybe_flush(&mut self) {
+        if self.up + self.down >= BYTES_FLUSH_THRESHOLD {
+            self.flush_bytes();
+        }
+    }
+
+    fn flush_bytes(&mut self) {
+        if self.up + self.down > 0 {
+            self.stats.add_bytes(self.up, self.down);
+            self.stats.clients().add_bytes(self.client, self.up, self.down);
+            self.up = 0;
+            self.down = 0;
+        }
+    }
+}
+
+impl<T> Drop for CountingIo<T> {
+    fn drop(&mut self) {
+        self.flush_bytes();
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CountingIo<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if res.is_ready() {
+            this.up += (buf.filled().len() - before) as u64;
+            this.maybe_flush();
+        }
+        res
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CountingIo<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            this.down += n as u64;
+            this.maybe_flush();
+        }
+        res
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(n)) = res {
+            this.down += n as u64;
+            this.maybe_flush();
+        }
+        res
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// 响应体包装：被 hyper 发送完毕并释放时记录整个请求的耗时
+struct TimedBody<B> {
+    inner: B,
+    started: Instant,
+    stats: Arc<Stats>,
+}
+
+impl<B> TimedBody<B> {
+    fn new(inner: B, started: Instant, stats: Arc<Stats>) -> Self {
+        Self { inner, started, stats }
+    }
+}
+
+impl<B> Drop for TimedBody<B> {
+    fn drop(&mut self) {
+        self.stats.record_total(self.started.elapsed());
+    }
+}
+
+impl<B: Body + Unpin> Body for TimedBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Pin::new(&mut self.get_mut().inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::firewall::FirewallManager;
+    use crate::outbound::SocketOptions;
+    use crate::stats::Format;
+
+    async fn read_head(stream: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    /// 上游：收下一个请求头交给测试比对，并应答空的 200。
+    /// 不发数据就关闭的连接跳过（CONNECT 预先建立、隧道内为 HTTP 时弃用的连接）
+    async fn capture_upstream() -> (SocketAddr, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    match stream.read_u8().await {
+                        Ok(b) => head.push(b),
+                        Err(_) => break,
+                    }
+                }
+                if head.ends_with(b"\r\n\r\n") {
+                    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+                    return String::from_utf8(head).unwrap();
+                }
+            }
+        });
+        (addr, task)
+    }
+
+    /// 上游：同一连接上逐个应答空的 200，保持长连接
+    async fn keepalive_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    loop {
+                        let mut head = Vec::new();
+                        while !head.ends_with(b"\r\n\r\n") {
+                            match stream.read_u8().await {
+                                Ok(b) => head.push(b),
+                                Err(_) => return,
+                            }
+                        }
+                        if stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    fn outbound() -> Outbound {
+        Outbound::new(None, Vec::new(), SocketOptions::default(), Vec::new())
+    }
+
+    /// 测试中的上游都监听在回环地址上，正式配置会拒绝这些目标
+    fn permissive() -> Outbound {
+        outbound().allow_local_targets()
+    }
+
+    /// 按显式代理模式的正式路径（访问控制、目标解析与检查、转发）服务一个客户端连接
+    async fn serve_proxy(args: &[&str], outbound: Outbound) -> (SocketAddr, Arc<Stats>) {
+        let argv = ["uaforge", "--mode", "forward", "-u", "Forged/1.0"];
+        let config = Config::try_from_args(argv.iter().chain(args)).unwrap();
+        let access = Arc::new(ForwardAccess::new(&config));
+        let stats = Arc::new(Stats::new(0, 0));
+        let fw = Arc::new(FirewallManager::in_memory(config.firewall.clone(), stats.clone()));
+        let handler = Arc::new(HttpHandler::new(config, stats.clone(), fw).unwrap());
+        let outbound = Arc::new(outbound);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let conn_stats = stats.clone();
+        tokio::spawn(async move {
+            let (client, peer) = listener.accept().await.unwrap();
+            let _ = handle_forward_connection(client, peer, access, handler, conn_stats, outbound).await;
+        });
+        (addr, stats)
+    }
+
+    /// 发送一个请求，返回连接和响应头
+    async fn request(proxy: SocketAddr, req: &str) -> (TcpStream, String) {
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(req.as_bytes()).await.unwrap();
+        let head = read_head(&mut client).await;
+        (client, head)
+    }
+
+    fn stat(stats: &Stats, key: &str) -> String {
+        let out = stats.render(Format::Kv);
+        let prefix = format!("{key}:");
+        out.lines().find_map(|l| l.strip_prefix(&prefix)).unwrap().to_string()
+    }
+
+    // 解析失败说明请求已通过访问控制
+    const UNRESOLVABLE: &str = "GET http://uaforge.invalid/ HTTP/1.1\r\nHost: uaforge.invalid\r\n";
+
+    #[tokio::test]
+    async fn forward_refuses_sources_outside_allow_list() {
+        let (proxy, _) = serve_proxy(&["--forward-allow", "10.0.0.0/8"], outbound()).await;
+        let (_, head) = request(proxy, &format!("{UNRESOLVABLE}\r\n")).await;
+        assert!(head.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn forward_requires_basic_auth() {
+        let args = ["--forward-user", "alice", "--forward-pass", "secret"];
+
+        let (proxy, _) = serve_proxy(&args, outbound()).await;
+        let (_, head) = request(proxy, &format!("{UNRESOLVABLE}\r\n")).await;
+        assert!(head.starts_with("HTTP/1.1 407 Proxy Authentication Required\r\n"), "{head}");
+        assert!(head.contains("proxy-authenticate: Basic realm=\"uaforge\"\r\n"), "{head}");
+
+        // base64("alice:wrong")
+        let (proxy, _) = serve_proxy(&args, outbound()).await;
+        let wrong = format!("{UNRESOLVABLE}Proxy-Authorization: Basic YWxpY2U6d3Jvbmc=\r\n\r\n");
+        let (_, head) = request(proxy, &wrong).await;
+        assert!(head.starts_with("HTTP/1.1 407 "), "{head}");
+
+        // base64("alice:secret")
+        let (proxy, _) = serve_proxy(&args, outbound()).await;
+        let right = format!("{UNRESOLVABLE}Proxy-Authorization: basic YWxpY2U6c2VjcmV0\r\n\r\n");
+        let (_, head) = request(proxy, &right).await;
+        assert!(head.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn forward_keeps_header_order_and_case() {
+        let (upstream_addr, upstream) = capture_upstream().await;
+        let (proxy, _) = serve_proxy(&[], permissive()).await;
+
+        let (_, head) = request(
+            proxy,
+            &format!(
+                "GET http://{upstream_addr}/path?q=1 HTTP/1.1\r\n\
+                 X-First: 1\r\n\
+                 Proxy-Connection: keep-alive\r\n\
+                 host: example.test\r\n\
+                 x-MiXed-Case: a\r\n\
+                 Proxy-Authorization: Basic Zm9vOmJhcg==\r\n\
+                 User-Agent: Mozilla/5.0 (Windows NT 10.0; Win64; x64)\r\n\
+                 Accept: */*\r\n\
+                 X-Last: z\r\n\
+                 \r\n"
+            ),
+        )
+        .await;
+
+        // 代理头被删除，其余头的顺序与大小写不变，UA 原位改写
+        assert_eq!(
+            upstream.await.unwrap(),
+            "GET /path?q=1 HTTP/1.1\r\n\
+             X-First: 1\r\n\
+             host: example.test\r\n\
+             x-MiXed-Case: a\r\n\
+             User-Agent: Forged/1.0\r\n\
+             Accept: */*\r\n\
+             X-Last: z\r\n\
+             \r\n"
+        );
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn forward_upgrade_rewrites_ua_and_tunnels() {
+        // 上游：收下握手请求，应答 101 后回显一段数据再主动发一段
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = listener.local_addr().unwrap();
+        let upstream = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let head = read_head(&mut stream).await;
+            stream
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n")
+                .await
+                .unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.write_all(b"from upstream").await.unwrap();
+            head
+        });
+        let (proxy, stats) = serve_proxy(&[], permissive()).await;
+
+        let (mut client, head) = request(
+            proxy,
+            &format!(
+                "GET http://{upstream_addr}/chat HTTP/1.1\r\n\
+                 Host: example.test\r\n\
+                 Connection: Upgrade\r\n\
+                 Upgrade: websocket\r\n\
+                 User-Agent: Mozilla/5.0 (Windows NT 10.0; Win64; x64)\r\n\
+                 \r\n"
+            ),
+        )
+        .await;
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{head}");
+
+        client.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+        let mut pushed = [0u8; 13];
+        client.read_exact(&mut pushed).await.unwrap();
+        assert_eq!(&pushed, b"from upstream");
+
+        assert_eq!(
+            upstream.await.unwrap(),
+            "GET /chat HTTP/1.1\r\n\
+             Host: example.test\r\n\
+             Connection: Upgrade\r\n\
+             Upgrade: websocket\r\n\
+             User-Agent: Forged/1.0\r\n\
+             \r\n"
+        );
+        assert_eq!(stat(&stats, "upgraded_connections"), "1");
+    }
+
+    #[tokio::test]
+    async fn forward_refuses_loopback_targets() {
+        let (upstream_addr, _upstream) = capture_upstream().await;
+        let (proxy, _) = serve_proxy(&[], outbound()).await;
+        let (_, head) = request(proxy, &format!("GET http://{upstream_addr}/ HTTP/1.1\r\n\r\n")).await;
+        assert!(head.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn upstream_timeout_replies_504() {
+        // 上游接受连接但从不应答
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = listener.local_addr().unwrap();
+        let upstream = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_head(&mut stream).await;
+            std::future::pending::<()>().await;
+        });
+        let (proxy, _) = serve_proxy(&["--upstream-timeout", "1s"], permissive()).await;
+
+        let (_, head) = request(proxy, &format!("GET http://{upstream_addr}/ HTTP/1.1\r\n\r\n")).await;
+        assert!(head.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"), "{head}");
+        assert!(head.contains("connection: close\r\n"), "{head}");
+        upstream.abort();
+    }
+
+    #[tokio::test]
+    async fn connect_requires_port() {
+        let (proxy, _) = serve_proxy(&[], permissive()).await;
+        let (_, head) = request(proxy, "CONNECT example.test HTTP/1.1\r\nHost: example.test\r\n\r\n").await;
+        assert!(head.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn connect_refuses_loopback_targets() {
+        let (upstream_addr, _upstream) = capture_upstream().await;
+        let (proxy, _) = serve_proxy(&[], outbound()).await;
+        let (_, head) = request(proxy, &format!("CONNECT {upstream_addr} HTTP/1.1\r\n\r\n")).await;
+        assert!(head.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{head}");
+    }
+
+    #[tokio::test]
+    async fn connect_tunnel_rewrites_plain_http() {
+        let (upstream_addr, upstream) = capture_upstream().await;
+        let (proxy, stats) = serve_proxy(&[], permissive()).await;
+
+        let (mut client, head) = request(proxy, &format!("CONNECT {upstream_addr} HTTP/1.1\r\n\r\n")).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+
+        // 隧道内的明文请求与透明代理一样改写 UA
+        client
+            .write_all(b"GET /path HTTP/1.1\r\nHost: example.test\r\nUser-Agent: Mozilla/5.0 (Windows NT 10.0)\r\n\r\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            upstream.await.unwrap(),
+            "GET /path HTTP/1.1\r\nHost: example.test\r\nUser-Agent: Forged/1.0\r\n\r\n"
+        );
+        assert!(read_head(&mut client).await.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(stat(&stats, "connect_tunnels"), "1");
+        assert_eq!(stat(&stats, "non_http_connections"), "0");
+    }
+
+    #[tokio::test]
+    async fn connect_tunnel_forwards_non_http() {
+        // 回显上游
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut r, mut w) = stream.split();
+            let _ = tokio::io::copy(&mut r, &mut w).await;
+        });
+        let (proxy, stats) = serve_proxy(&[], permissive()).await;
+
+        let (mut client, head) = request(proxy, &format!("CONNECT {upstream_addr} HTTP/1.1\r\n\r\n")).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+
+        // TLS ClientHello 的记录头，检测读出的字节同样要转发给上游
+        let hello = [0x16, 0x03, 0x01, 0x00, 0x05, 0x01, 0x00, 0x00, 0x01, 0x00];
+        client.write_all(&hello).await.unwrap();
+        let mut echoed = [0u8; 10];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, hello);
+        assert_eq!(stat(&stats, "non_http_connections"), "1");
+    }
+
+    /// 防火墙白名单命中：条目写入集合前照常转发，写入后在同一长连接上再发一个请求，
+    /// 返回该连接供测试检查动作的结果
+    async fn fw_drop(action: &str) -> (TcpStream, Arc<Stats>) {
+        let upstream = keepalive_upstream().await;
+        let args = [
+            "--fw-type", "nft", "--fw-set-name", "test", "--fw-ua-w", "Whitelisted",
+            "--fw-drop", "--fw-drop-action", action,
+        ];
+        let (proxy, stats) = serve_proxy(&args, permissive()).await;
+        let req = format!(
+            "GET http://{upstream}/path?q=1 HTTP/1.1\r\nHost: example.test\r\nUser-Agent: Whitelisted/1.0\r\n\r\n"
+        );
+
+        // 批次尚未写入：断开只会让客户端重连回来再次命中
+        let (mut client, head) = request(proxy, &req).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert_eq!(stat(&stats, "fw_drops"), "0");
+
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        client.write_all(req.as_bytes()).await.unwrap();
+        (client, stats)
+    }
+
+    #[tokio::test]
+    async fn fw_drop_rst_resets_connection() {
+        let (mut client, stats) = fw_drop("rst").await;
+        let mut buf = [0u8; 64];
+        let err = client.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(stat(&stats, "fw_drops"), "1");
+    }
+
+    #[tokio::test]
+    async fn fw_drop_503_sets_retry_after() {
+        let (mut client, stats) = fw_drop("503").await;
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{head}");
+        assert!(head.contains("retry-after: 1\r\n"), "{head}");
+        assert!(head.contains("connection: close\r\n"), "{head}");
+        assert_eq!(stat(&stats, "fw_drops"), "1");
+    }
+
+    #[tokio::test]
+    async fn fw_drop_redirects_to_same_url() {
+        let (mut client, stats) = fw_drop("redirect").await;
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 307 Temporary Redirect\r\n"), "{head}");
+        assert!(head.contains("location: http://example.test/path?q=1\r\n"), "{head}");
+        assert!(head.contains("connection: close\r\n"), "{head}");
+        assert_eq!(stat(&stats, "fw_drops"), "1");
+    }
+
+    #[tokio::test]
+    async fn fw_drop_pass_forwards_then_closes() {
+        let (mut client, stats) = fw_drop("pass").await;
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert!(head.contains("connection: close\r\n"), "{head}");
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(stat(&stats, "fw_drops"), "1");
+    }
+
+    #[test]
+    fn listen_address_target_is_a_loop() {
+        let stats = Stats::new(0, 0);
+        let listen = vec!["0.0.0.0:8080".parse().unwrap()];
+        let outbound = Outbound::new(None, Vec::new(), SocketOptions::default(), listen);
+        let client: std::net::IpAddr = "192.168.1.10".parse().unwrap();
+        let local: SocketAddr = "192.168.1.1:8080".parse().unwrap();
+
+        let status = refuse_target(&outbound, &stats, client, "192.168.1.1:8080".parse().unwrap(), local);
+        assert_eq!(status, Some(StatusCode::LOOP_DETECTED));
+        assert_eq!(stat(&stats, "loops_refused"), "1");
+
+        // 其他本机地址只是拒绝，不计为环路
+        let status = refuse_target(&outbound, &stats, client, "127.0.0.1:9000".parse().unwrap(), local);
+        assert_eq!(status, Some(StatusCode::FORBIDDEN));
+        assert_eq!(stat(&stats, "loops_refused"), "1");
+    }
+
+    #[tokio::test]
+    async fn own_outbound_connection_is_refused() {
+        let argv = ["uaforge", "-u", "Forged/1.0"];
+        let config = Config::try_from_args(argv).unwrap();
+        let stats = Arc::new(Stats::new(0, 0));
+        let fw = Arc::new(FirewallManager::in_memory(config.firewall.clone(), stats.clone()));
+        let handler = Arc::new(HttpHandler::new(config, stats.clone(), fw).unwrap());
+        let outbound = Arc::new(outbound());
+
+        // 出站连接被重定向回本进程的监听端口
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut looped = outbound.connect(listener.local_addr().unwrap()).await.unwrap();
+        let (client, peer) = listener.accept().await.unwrap();
+        looped.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+
+        let err = handle_connection(client, peer, Inbound::Socks5(None), handler, stats.clone(), outbound.clone())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("our own outbound connection"), "{err}");
+        assert_eq!(stat(&stats, "loops_refused"), "1");
+
+        // 出站连接关闭后登记随之移除
+        drop(looped);
+        assert!(!outbound.is_own_connection(peer));
+    }
+
+    #[tokio::test]
+    async fn rewind_replays_head() {
+        let mut io = Rewind::new(Bytes::from_static(b"GET "), &b"/ HTTP/1.1"[..]);
+        let mut out = String::new();
+        io.read_to_string(&mut out).await.unwrap();
+        assert_eq!(out, "GET / HTTP/1.1");
+    }
+
+    #[test]
+    fn strip_headers_keeps_order() {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [("a", "1"), ("proxy-connection", "x"), ("b", "2"), ("a", "3"), ("c", "4")] {
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        strip_headers(&mut headers, &["proxy-connection"]);
+        let order: Vec<(&str, &str)> = headers
+            .iter()
+            .map(|(n, v)| (n.as_str(), v.to_str().unwrap()))
+            .collect();
+        assert_eq!(order, [("a", "1"), ("a", "3"), ("b", "2"), ("c", "4")]);
+    }
+}
@@ -0,0 +1,327 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// 握手必须在这段时间内完成，避免空连接长期占用并发名额
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xFF;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_NOT_ALLOWED: u8 = 0x02;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// 用户名 / 密码认证（RFC 1929）
+#[derive(Clone)]
+pub struct Auth {
+    pub user: String,
+    pub pass: String,
+}
+
+// 配置会经管理接口 /config 输出，不打印密码
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Auth").field("user", &self.user).field("pass", &"***").finish()
+    }
+}
+
+/// 完成 SOCKS5 握手（RFC 1928，仅支持 CONNECT），返回客户端请求的目标地址。
+///
+/// permit 对解析后的目标返回 false 时应答“规则不允许”并返回错误。
+/// 成功应答在连接上游之前发出：HTTP 流量按请求逐个连接上游，
+/// 上游不可达时由后续的 HTTP / 非 HTTP 转发路径关闭连接。
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    auth: Option<&Auth>,
+    permit: impl FnOnce(SocketAddr) -> bool,
+) -> io::Result<SocketAddr> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, negotiate(stream, auth, permit))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "SOCKS5 handshake timed out"))?
+}
+
+async fn negotiate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    auth: Option<&Auth>,
+    permit: impl FnOnce(SocketAddr) -> bool,
+) -> io::Result<SocketAddr> {
+    // 方法协商：VER NMETHODS METHODS...
+    let [ver, nmethods] = read_array(stream).await?;
+    if ver != VERSION {
+        return Err(invalid(format!("unsupported SOCKS version: {}", ver)));
+    }
+    let mut methods = vec![0u8; nmethods as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let method = if auth.is_some() { METHOD_USER_PASS } else { METHOD_NO_AUTH };
+    if !methods.contains(&method) {
+        stream.write_all(&[VERSION, METHOD_NONE_ACCEPTABLE]).await?;
+        return Err(invalid("no acceptable SOCKS5 auth method".to_string()));
+    }
+    stream.write_all(&[VERSION, method]).await?;
+
+    if let Some(auth) = auth {
+        authenticate(stream, auth).await?;
+    }
+
+    // 请求：VER CMD RSV ATYP DST.ADDR DST.PORT
+    let [ver, cmd, _rsv, atyp] = read_array(stream).await?;
+    if ver != VERSION {
+        return Err(invalid(format!("unsupported SOCKS version: {}", ver)));
+    }
+    if cmd != CMD_CONNECT {
+        reply(stream, REP_COMMAND_NOT_SUPPORTED).await?;
+        return Err(invalid(format!("unsupported SOCKS5 command: {}", cmd)));
+    }
+
+    let dest = match atyp {
+        ATYP_IPV4 => {
+            let ip: [u8; 4] = read_array(stream).await?;
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), read_port(stream).await?)
+        }
+        ATYP_IPV6 => {
+            let ip: [u8; 16] = read_array(stream).await?;
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), read_port(stream).await?)
+        }
+        ATYP_DOMAIN => {
+            let [len] = read_array(stream).await?;
+            let mut host = vec![0u8; len as usize];
+            stream.read_exact(&mut host).await?;
+            let port = read_port(stream).await?;
+            let host = String::from_utf8(host).map_err(|_| invalid("invalid SOCKS5 domain".to_string()))?;
+            match tokio::net::lookup_host((host.as_str(), port)).await.ok().and_then(|mut a| a.next()) {
+                Some(addr) => addr,
+                None => {
+                    reply(stream, REP_HOST_UNREACHABLE).await?;
+                    return Err(io::Error::other(format!("cannot resolve {}", host)));
+                }
+            }
+        }
+        _ => {
+            reply(stream, REP_ADDRESS_NOT_SUPPORTED).await?;
+            return Err(invalid(format!("unsupported SOCKS5 address type: {}", atyp)));
+        }
+    };
+
+    if !permit(dest) {
+        reply(stream, REP_NOT_ALLOWED).await?;
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("SOCKS5 destination not allowed: {}", dest)));
+    }
+
+    reply(stream, REP_SUCCEEDED).await?;
+    Ok(dest)
+}
+
+/// 作为客户端经 SOCKS5 上游代理连接目标（出站链式代理），握手完成后 stream 即为隧道
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, dest: SocketAddr, auth: Option<&Auth>) -> io::Result<()> {
+    let method = if auth.is_some() { METHOD_USER_PASS } else { METHOD_NO_AUTH };
+    stream.write_all(&[VERSION, 1, method]).await?;
+    let [ver, chosen] = read_array(stream).await?;
+    if ver != VERSION || chosen != method {
+        return Err(invalid("upstream SOCKS5 proxy rejected auth method".to_string()));
+    }
+
+    if let Some(auth) = auth {
+        let (user, pass) = (auth.user.as_bytes(), auth.pass.as_bytes());
+        if user.len() > 255 || pass.len() > 255 {
+            return Err(invalid("SOCKS5 username/password too long".to_string()));
+        }
+        let mut msg = vec![AUTH_VERSION, user.len() as u8];
+        msg.extend_from_slice(user);
+        msg.push(pass.len() as u8);
+        msg.extend_from_slice(pass);
+        stream.write_all(&msg).await?;
+        let [_, status] = read_array(stream).await?;
+        if status != 0x00 {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "upstream SOCKS5 authentication failed"));
+        }
+    }
+
+    let mut msg = vec![VERSION, CMD_CONNECT, 0x00];
+    match dest.ip() {
+        IpAddr::V4(ip) => {
+            msg.push(ATYP_IPV4);
+            msg.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            msg.push(ATYP_IPV6);
+            msg.extend_from_slice(&ip.octets());
+        }
+    }
+    msg.extend_from_slice(&dest.port().to_be_bytes());
+    stream.write_all(&msg).await?;
+
+    // 应答：VER REP RSV ATYP BND.ADDR BND.PORT，绑定地址读掉即可
+    let [_, rep, _, atyp] = read_array(stream).await?;
+    if rep != REP_SUCCEEDED {
+        return Err(io::Error::other(format!("upstream SOCKS5 CONNECT to {} failed: reply {}", dest, rep)));
+    }
+    let addr_len = match atyp {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => read_array::<1, _>(stream).await?[0] as usize,
+        _ => return Err(invalid(format!("unsupported SOCKS5 address type: {}", atyp))),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+/// 子协商：VER ULEN UNAME PLEN PASSWD
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, auth: &Auth) -> io::Result<()> {
+    let [ver, ulen] = read_array(stream).await?;
+    if ver != AUTH_VERSION {
+        return Err(invalid(format!("unsupported SOCKS5 auth version: {}", ver)));
+    }
+    let mut user = vec![0u8; ulen as usize];
+    stream.read_exact(&mut user).await?;
+    let [plen] = read_array(stream).await?;
+    let mut pass = vec![0u8; plen as usize];
+    stream.read_exact(&mut pass).await?;
+
+    if user != auth.user.as_bytes() || pass != auth.pass.as_bytes() {
+        stream.write_all(&[AUTH_VERSION, 0x01]).await?;
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS5 authentication failed"));
+    }
+    stream.write_all(&[AUTH_VERSION, 0x00]).await
+}
+
+/// 应答中的绑定地址固定为 0.0.0.0:0，客户端不使用它
+async fn reply<S: AsyncWrite + Unpin>(stream: &mut S, rep: u8) -> io::Result<()> {
+    stream
+        .write_all(&[VERSION, rep, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+async fn read_port<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<u16> {
+    Ok(u16::from_be_bytes(read_array(stream).await?))
+}
+
+async fn read_array<const N: usize, S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+
+    fn auth() -> Auth {
+        Auth { user: "user".to_string(), pass: "secret".to_string() }
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// 服务端在后台握手，返回客户端一侧和握手结果
+    fn serve(
+        auth: Option<Auth>,
+        allowed: bool,
+    ) -> (DuplexStream, tokio::task::JoinHandle<io::Result<SocketAddr>>) {
+        let (client, mut server) = duplex(1024);
+        let task = tokio::spawn(async move { handshake(&mut server, auth.as_ref(), |_| allowed).await });
+        (client, task)
+    }
+
+    #[tokio::test]
+    async fn connect_without_auth() {
+        let (mut client, server) = serve(None, true);
+        connect(&mut client, addr("203.0.113.1:443"), None).await.unwrap();
+        assert_eq!(server.await.unwrap().unwrap(), addr("203.0.113.1:443"));
+    }
+
+    #[tokio::test]
+    async fn connect_ipv6_with_auth() {
+        let (mut client, server) = serve(Some(auth()), true);
+        connect(&mut client, addr("[2001:db8::1]:8080"), Some(&auth())).await.unwrap();
+        assert_eq!(server.await.unwrap().unwrap(), addr("[2001:db8::1]:8080"));
+    }
+
+    #[tokio::test]
+    async fn wrong_password() {
+        let (mut client, server) = serve(Some(auth()), true);
+        let wrong = Auth { pass: "guess".to_string(), ..auth() };
+        let err = connect(&mut client, addr("203.0.113.1:443"), Some(&wrong)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(server.await.unwrap().unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn auth_required_but_not_offered() {
+        let (mut client, server) = serve(Some(auth()), true);
+        client.write_all(&[VERSION, 1, METHOD_NO_AUTH]).await.unwrap();
+        assert_eq!(read_array::<2, _>(&mut client).await.unwrap(), [VERSION, METHOD_NONE_ACCEPTABLE]);
+        assert_eq!(server.await.unwrap().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn destination_not_allowed() {
+        let (mut client, server) = serve(None, false);
+        let err = connect(&mut client, addr("127.0.0.1:9000"), None).await.unwrap_err();
+        assert!(err.to_string().contains(&format!("reply {}", REP_NOT_ALLOWED)), "{err}");
+        assert_eq!(server.await.unwrap().unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn unsupported_command() {
+        let (mut client, server) = serve(None, true);
+        client.write_all(&[VERSION, 1, METHOD_NO_AUTH]).await.unwrap();
+        assert_eq!(read_array::<2, _>(&mut client).await.unwrap(), [VERSION, METHOD_NO_AUTH]);
+        // BIND
+        client.write_all(&[VERSION, 0x02, 0x00, ATYP_IPV4, 203, 0, 113, 1, 0, 80]).await.unwrap();
+        let reply: [u8; 10] = read_array(&mut client).await.unwrap();
+        assert_eq!(reply[1], REP_COMMAND_NOT_SUPPORTED);
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn unsupported_address_type() {
+        let (mut client, server) = serve(None, true);
+        client.write_all(&[VERSION, 1, METHOD_NO_AUTH]).await.unwrap();
+        read_array::<2, _>(&mut client).await.unwrap();
+        client.write_all(&[VERSION, CMD_CONNECT, 0x00, 0x09]).await.unwrap();
+        let reply: [u8; 10] = read_array(&mut client).await.unwrap();
+        assert_eq!(reply[1], REP_ADDRESS_NOT_SUPPORTED);
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_socks4() {
+        let (mut client, server) = serve(None, true);
+        client.write_all(&[0x04, 0x01, 0x00, 0x50]).await.unwrap();
+        assert_eq!(server.await.unwrap().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn truncated_request() {
+        let (mut client, server) = serve(None, true);
+        client.write_all(&[VERSION, 1, METHOD_NO_AUTH]).await.unwrap();
+        read_array::<2, _>(&mut client).await.unwrap();
+        client.write_all(&[VERSION, CMD_CONNECT, 0x00, ATYP_IPV4, 203, 0]).await.unwrap();
+        drop(client);
+        assert_eq!(server.await.unwrap().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
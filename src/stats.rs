@@ -5,6 +5,91 @@ use std::thread;
 use std::time::{Duration, Instant};
 use std::sync::atomic::AtomicBool;
 
+use crate::clients::ClientTable;
+use crate::topn::TopN;
+
+// 高频条目文件中每类输出的条目数
+const TOP_FILE_ENTRIES: usize = 20;
+
+/// 统计输出格式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// 每行 `key:value`（LuCI 使用的原格式）
+    Kv,
+    /// 单行 JSON 对象，附带进程信息
+    Json,
+    /// 不写统计文件
+    Off,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "kv" => Ok(Format::Kv),
+            "json" => Ok(Format::Json),
+            "off" => Ok(Format::Off),
+            _ => Err(format!("invalid stats format: {} (expected kv/json/off)", s)),
+        }
+    }
+}
+
+// 直方图桶上限（微秒），对数刻度；最后一个桶收集超过 10s 的样本
+const HISTOGRAM_BOUNDS_US: [u32; 17] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 5_000_000, 10_000_000,
+];
+const HISTOGRAM_BUCKETS: usize = HISTOGRAM_BOUNDS_US.len() + 1;
+
+/// 固定分桶的耗时直方图，每个桶一个 AtomicUsize，32 位平台上同样无锁。
+/// 分位数取所在桶的上限，精度为桶宽；溢出桶按最大上限计。
+pub struct Histogram {
+    buckets: [AtomicUsize; HISTOGRAM_BUCKETS],
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn record(&self, d: Duration) {
+        let us = d.as_micros();
+        let idx = HISTOGRAM_BOUNDS_US
+            .iter()
+            .position(|&b| us <= b as u128)
+            .unwrap_or(HISTOGRAM_BOUNDS_US.len());
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// (样本数, p50, p90, p99)，分位数单位为毫秒
+    fn summary(&self) -> (u64, f64, f64, f64) {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed) as u64)
+            .collect();
+        let total: u64 = counts.iter().sum();
+        let percentile = |p: u64| -> f64 {
+            if total == 0 {
+                return 0.0;
+            }
+            // 第 rank 个样本所在的桶
+            let rank = (total * p).div_ceil(100).max(1);
+            let mut seen = 0;
+            for (idx, c) in counts.iter().enumerate() {
+                seen += c;
+                if seen >= rank {
+                    let bound = HISTOGRAM_BOUNDS_US[idx.min(HISTOGRAM_BOUNDS_US.len() - 1)];
+                    return bound as f64 / 1000.0;
+                }
+            }
+            0.0
+        };
+        (total, percentile(50), percentile(90), percentile(99))
+    }
+}
+
 pub struct Stats {
     // NOTE: mipsel_24kc does not guarantee 64-bit atomics, so use AtomicUsize for portability.
     // These counters may wrap on 32-bit targets; this is acceptable for runtime stats display.
@@ -13,19 +98,71 @@ pub struct Stats {
     modified_requests: AtomicUsize,
     cache_hit_modify: AtomicUsize,
     cache_hit_pass: AtomicUsize,
+    cache_expired: AtomicUsize,
+    fw_dry_run_nonhttp: AtomicUsize,
+    fw_dry_run_ua_whitelist: AtomicUsize,
+    upstream_connect_failures: AtomicUsize,
+    http_parse_errors: AtomicUsize,
+    non_http_connections: AtomicUsize,
+    upgraded_connections: AtomicUsize,
+    connect_tunnels: AtomicUsize,
+    loops_refused: AtomicUsize,
+    whitelist_hits: AtomicUsize,
+    fw_whitelist_hits: AtomicUsize,
+    fw_drops: AtomicUsize,
+    fw_offload_added: AtomicUsize,
+    fw_batch_failures: AtomicUsize,
+    // 转发字节数 (客户端 -> 上游, 上游 -> 客户端)。usize 在 32 位平台上很快回绕，
+    // 因此用锁保护的 u64，由连接侧按块累加，锁竞争可以忽略
+    bytes: Mutex<(u64, u64)>,
+    // 单请求耗时：上游建连、modify_request、上游响应头（TTFB）、整个请求（至响应体结束）
+    latency_connect: Histogram,
+    latency_modify: Histogram,
+    latency_ttfb: Histogram,
+    latency_total: Histogram,
+    top: TopN,
+    clients: ClientTable,
+    // 最近一次写入周期计算出的 RPS（×100 保存），供管理接口读取
+    last_rps_centi: AtomicUsize,
+    started: Instant,
     stop: AtomicBool,
     writer_handle: Mutex<Option<thread::JoinHandle<()>>>,
     stop_cond: Condvar,
 }
 
 impl Stats {
-    pub fn new() -> Self {
+    /// top_capacity 为高频条目报告每类跟踪的条目数，client_capacity 为按客户端统计的
+    /// 最大客户端数，0 均表示禁用
+    pub fn new(top_capacity: usize, client_capacity: usize) -> Self {
         Self {
             active_connections: AtomicUsize::new(0),
             http_requests: AtomicUsize::new(0),
             modified_requests: AtomicUsize::new(0),
             cache_hit_modify: AtomicUsize::new(0),
             cache_hit_pass: AtomicUsize::new(0),
+            cache_expired: AtomicUsize::new(0),
+            fw_dry_run_nonhttp: AtomicUsize::new(0),
+            fw_dry_run_ua_whitelist: AtomicUsize::new(0),
+            upstream_connect_failures: AtomicUsize::new(0),
+            http_parse_errors: AtomicUsize::new(0),
+            non_http_connections: AtomicUsize::new(0),
+            upgraded_connections: AtomicUsize::new(0),
+            connect_tunnels: AtomicUsize::new(0),
+            loops_refused: AtomicUsize::new(0),
+            whitelist_hits: AtomicUsize::new(0),
+            fw_whitelist_hits: AtomicUsize::new(0),
+            fw_drops: AtomicUsize::new(0),
+            fw_offload_added: AtomicUsize::new(0),
+            fw_batch_failures: AtomicUsize::new(0),
+            bytes: Mutex::new((0, 0)),
+            latency_connect: Histogram::new(),
+            latency_modify: Histogram::new(),
+            latency_ttfb: Histogram::new(),
+            latency_total: Histogram::new(),
+            top: TopN::new(top_capacity),
+            clients: ClientTable::new(client_capacity),
+            last_rps_centi: AtomicUsize::new(0),
+            started: Instant::now(),
             stop: AtomicBool::new(false),
             writer_handle: Mutex::new(None),
             stop_cond: Condvar::new(),
@@ -56,9 +193,216 @@ impl Stats {
         self.cache_hit_pass.fetch_add(1, Ordering::Relaxed);
     }
 
-    pub fn start_writer(self: &Arc<Self>, path: &str, interval: Duration) {
+    pub fn inc_cache_expired(&self) {
+        self.cache_expired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_fw_dry_run_nonhttp(&self) {
+        self.fw_dry_run_nonhttp.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_fw_dry_run_ua_whitelist(&self) {
+        self.fw_dry_run_ua_whitelist.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_upstream_connect_failures(&self) {
+        self.upstream_connect_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_http_parse_errors(&self) {
+        self.http_parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_non_http(&self) {
+        self.non_http_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_upgrades(&self) {
+        self.upgraded_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_connect_tunnels(&self) {
+        self.connect_tunnels.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_loops_refused(&self) {
+        self.loops_refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_whitelist_hits(&self) {
+        self.whitelist_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_fw_whitelist_hits(&self) {
+        self.fw_whitelist_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_fw_drops(&self) {
+        self.fw_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_fw_offload_added(&self, n: usize) {
+        self.fw_offload_added.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn inc_fw_batch_failures(&self) {
+        self.fw_batch_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes(&self, up: u64, down: u64) {
+        if let Ok(mut b) = self.bytes.lock() {
+            b.0 += up;
+            b.1 += down;
+        }
+    }
+
+    /// 高频条目报告（UA、Host、客户端、卸载条目）
+    pub fn top(&self) -> &TopN {
+        &self.top
+    }
+
+    /// 按客户端（源 IP）统计
+    pub fn clients(&self) -> &ClientTable {
+        &self.clients
+    }
+
+    pub fn record_connect(&self, d: Duration) {
+        self.latency_connect.record(d);
+    }
+
+    pub fn record_modify(&self, d: Duration) {
+        self.latency_modify.record(d);
+    }
+
+    pub fn record_ttfb(&self, d: Duration) {
+        self.latency_ttfb.record(d);
+    }
+
+    pub fn record_total(&self, d: Duration) {
+        self.latency_total.record(d);
+    }
+
+    /// 当前统计的 (键, 值) 列表，值已按输出格式化
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let active = self.active_connections.load(Ordering::Relaxed) as u64;
+        let http = self.http_requests.load(Ordering::Relaxed) as u64;
+        let modified = self.modified_requests.load(Ordering::Relaxed) as u64;
+        let cache_mod = self.cache_hit_modify.load(Ordering::Relaxed) as u64;
+        let cache_pass = self.cache_hit_pass.load(Ordering::Relaxed) as u64;
+        let dry_nonhttp = self.fw_dry_run_nonhttp.load(Ordering::Relaxed) as u64;
+        let dry_ua_w = self.fw_dry_run_ua_whitelist.load(Ordering::Relaxed) as u64;
+        let rps = self.last_rps_centi.load(Ordering::Relaxed) as f64 / 100.0;
+        let (bytes_up, bytes_down) = self.bytes.lock().map(|b| *b).unwrap_or_default();
+        let load = |c: &AtomicUsize| c.load(Ordering::Relaxed).to_string();
+
+        let total_cache = cache_mod + cache_pass;
+        let rule_processing = http.saturating_sub(total_cache);
+        let direct_pass = http.saturating_sub(modified);
+        let cache_ratio = if http > 0 {
+            (total_cache as f64) * 100.0 / (http as f64)
+        } else {
+            0.0
+        };
+
+        let mut fields = vec![
+            ("current_connections", active.to_string()),
+            ("total_requests", http.to_string()),
+            ("rps", format!("{rps:.2}")),
+            ("successful_modifications", modified.to_string()),
+            ("direct_passthrough", direct_pass.to_string()),
+            ("rule_processing", rule_processing.to_string()),
+            ("cache_hit_modify", cache_mod.to_string()),
+            ("cache_hit_pass", cache_pass.to_string()),
+            ("total_cache_ratio", format!("{cache_ratio:.2}")),
+            ("cache_expired", load(&self.cache_expired)),
+            ("fw_dry_run_nonhttp", dry_nonhttp.to_string()),
+            ("fw_dry_run_ua_whitelist", dry_ua_w.to_string()),
+            ("bytes_up", bytes_up.to_string()),
+            ("bytes_down", bytes_down.to_string()),
+            ("upstream_connect_failures", load(&self.upstream_connect_failures)),
+            ("http_parse_errors", load(&self.http_parse_errors)),
+            ("non_http_connections", load(&self.non_http_connections)),
+            ("upgraded_connections", load(&self.upgraded_connections)),
+            ("connect_tunnels", load(&self.connect_tunnels)),
+            ("loops_refused", load(&self.loops_refused)),
+            ("whitelist_hits", load(&self.whitelist_hits)),
+            ("fw_whitelist_hits", load(&self.fw_whitelist_hits)),
+            ("fw_drops", load(&self.fw_drops)),
+            ("fw_offload_added", load(&self.fw_offload_added)),
+            ("fw_batch_failures", load(&self.fw_batch_failures)),
+        ];
+
+        // (样本数键, [p50 键, p90 键, p99 键], 直方图)
+        let histograms: [(&'static str, [&'static str; 3], &Histogram); 4] = [
+            (
+                "latency_connect_count",
+                ["latency_connect_p50_ms", "latency_connect_p90_ms", "latency_connect_p99_ms"],
+                &self.latency_connect,
+            ),
+            (
+                "latency_modify_count",
+                ["latency_modify_p50_ms", "latency_modify_p90_ms", "latency_modify_p99_ms"],
+                &self.latency_modify,
+            ),
+            (
+                "latency_ttfb_count",
+                ["latency_ttfb_p50_ms", "latency_ttfb_p90_ms", "latency_ttfb_p99_ms"],
+                &self.latency_ttfb,
+            ),
+            (
+                "latency_total_count",
+                ["latency_total_p50_ms", "latency_total_p90_ms", "latency_total_p99_ms"],
+                &self.latency_total,
+            ),
+        ];
+        for (count_key, [p50_key, p90_key, p99_key], h) in histograms {
+            let (count, p50, p90, p99) = h.summary();
+            fields.push((count_key, count.to_string()));
+            fields.push((p50_key, format!("{p50:.2}")));
+            fields.push((p90_key, format!("{p90:.2}")));
+            fields.push((p99_key, format!("{p99:.2}")));
+        }
+        fields
+    }
+
+    /// 按指定格式输出当前统计。key:value 保持 LuCI 解析的旧格式；
+    /// JSON 额外带上进程信息（运行时长、版本、RSS、打开的文件描述符数）。
+    pub fn render(&self, format: Format) -> String {
+        let fields = self.fields();
+        match format {
+            Format::Off => String::new(),
+            Format::Kv => fields.iter().map(|(k, v)| format!("{k}:{v}\n")).collect(),
+            Format::Json => {
+                let mut out = format!(
+                    "{{\"version\":\"{}\",\"uptime\":{},\"rss_kb\":{},\"open_fds\":{}",
+                    crate::VERSION,
+                    self.started.elapsed().as_secs(),
+                    rss_kb().unwrap_or(0),
+                    open_fds().unwrap_or(0),
+                );
+                for (k, v) in &fields {
+                    out.push_str(&format!(",\"{k}\":{v}"));
+                }
+                out.push_str("}\n");
+                out
+            }
+        }
+    }
+
+    /// 定期采样 RPS 并写出统计文件，以及可选的高频条目文件和客户端统计文件
+    pub fn start_writer(
+        self: &Arc<Self>,
+        path: &str,
+        interval: Duration,
+        format: Format,
+        top_file: Option<&str>,
+        clients_file: Option<&str>,
+    ) {
+        // 即使不写任何文件也要启动：RPS 在这里按周期采样，管理接口 /stats 依赖它
         let stats = Arc::clone(self);
         let path = path.to_string();
+        let top_file = top_file.map(str::to_string);
+        let clients_file = clients_file.map(str::to_string);
         let handle = thread::spawn(move || {
             let mut last_http = 0u64;
             let mut last = Instant::now();
@@ -72,12 +416,7 @@ impl Stats {
                 if stats.stop.load(Ordering::Relaxed) {
                     return;
                 }
-                let active = stats.active_connections.load(Ordering::Relaxed) as u64;
                 let http = stats.http_requests.load(Ordering::Relaxed) as u64;
-                let modified = stats.modified_requests.load(Ordering::Relaxed) as u64;
-                let cache_mod = stats.cache_hit_modify.load(Ordering::Relaxed) as u64;
-                let cache_pass = stats.cache_hit_pass.load(Ordering::Relaxed) as u64;
-
                 let now = Instant::now();
                 let secs = now.duration_since(last).as_secs_f64();
                 let rps = if secs > 0.0 {
@@ -87,32 +426,18 @@ impl Stats {
                 };
                 last_http = http;
                 last = now;
+                stats
+                    .last_rps_centi
+                    .store((rps * 100.0) as usize, Ordering::Relaxed);
 
-                let total_cache = cache_mod + cache_pass;
-                let rule_processing = http.saturating_sub(total_cache);
-                let direct_pass = http.saturating_sub(modified);
-                let cache_ratio = if http > 0 {
-                    (total_cache as f64) * 100.0 / (http as f64)
-                } else {
-                    0.0
-                };
-
-                let content = format!(
-                    "current_connections:{active}\n\
-total_requests:{http}\n\
-rps:{rps:.2}\n\
-successful_modifications:{modified}\n\
-direct_passthrough:{direct_pass}\n\
-rule_processing:{rule_processing}\n\
-cache_hit_modify:{cache_mod}\n\
-cache_hit_pass:{cache_pass}\n\
-total_cache_ratio:{cache_ratio:.2}\n"
-                );
-
-                // 原子写入：先写临时文件，再 rename（避免 LuCI 读到半截）
-                let tmp_path = format!("{}.tmp", path);
-                if fs::write(&tmp_path, &content).is_ok() {
-                    let _ = fs::rename(&tmp_path, &path);
+                if format != Format::Off {
+                    write_atomic(&path, &stats.render(format));
+                }
+                if let Some(top_file) = &top_file {
+                    write_atomic(top_file, &stats.top.render(TOP_FILE_ENTRIES));
+                }
+                if let Some(clients_file) = &clients_file {
+                    write_atomic(clients_file, &stats.clients.render());
                 }
             }
         });
@@ -140,3 +465,107 @@ impl Drop for Stats {
         }
     }
 }
+
+/// 原子写入：先写临时文件，再 rename（避免 LuCI 读到半截）
+fn write_atomic(path: &str, content: &str) {
+    let tmp_path = format!("{}.tmp", path);
+    if fs::write(&tmp_path, content).is_ok() {
+        let _ = fs::rename(&tmp_path, path);
+    }
+}
+
+/// 常驻内存（/proc/self/status 中的 VmRSS，单位 kB）
+fn rss_kb() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix("VmRSS:"))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// 已打开的文件描述符数
+fn open_fds() -> Option<usize> {
+    Some(fs::read_dir("/proc/self/fd").ok()?.count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rps(stats: &Stats) -> String {
+        stats.fields().into_iter().find(|(k, _)| *k == "rps").unwrap().1
+    }
+
+    #[test]
+    fn rps_sampled_without_stats_file() {
+        let stats = Arc::new(Stats::new(0, 0));
+        stats.start_writer("/nonexistent/uaforge.stats", Duration::from_millis(100), Format::Off, None, None);
+        for _ in 0..10 {
+            stats.inc_http_requests();
+        }
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while rps(&stats) == "0.00" {
+            assert!(Instant::now() < deadline, "rps never sampled");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn record_us(h: &Histogram, us: u64, n: usize) {
+        for _ in 0..n {
+            h.record(Duration::from_micros(us));
+        }
+    }
+
+    #[test]
+    fn histogram_empty() {
+        assert_eq!(Histogram::new().summary(), (0, 0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn histogram_bucket_upper_bound() {
+        // 等于上限的样本落在该桶，超过一微秒就进入下一个桶
+        let h = Histogram::new();
+        record_us(&h, 1_000, 1);
+        assert_eq!(h.summary(), (1, 1.0, 1.0, 1.0));
+
+        let h = Histogram::new();
+        record_us(&h, 1_001, 1);
+        assert_eq!(h.summary(), (1, 2.5, 2.5, 2.5));
+
+        let h = Histogram::new();
+        record_us(&h, 0, 1);
+        assert_eq!(h.summary(), (1, 0.05, 0.05, 0.05));
+    }
+
+    #[test]
+    fn histogram_rank_rounds_up() {
+        // 10 个样本：p50 为第 5 个，p90 为第 9 个，p99 向上取整为第 10 个
+        let h = Histogram::new();
+        record_us(&h, 40, 5);
+        record_us(&h, 90, 4);
+        record_us(&h, 200, 1);
+        assert_eq!(h.summary(), (10, 0.05, 0.1, 0.25));
+
+        // 3 个样本：p50 为第 2 个
+        let h = Histogram::new();
+        record_us(&h, 40, 1);
+        record_us(&h, 90, 1);
+        record_us(&h, 200, 1);
+        assert_eq!(h.summary(), (3, 0.1, 0.25, 0.25));
+    }
+
+    #[test]
+    fn histogram_overflow_reports_max_bound() {
+        let h = Histogram::new();
+        record_us(&h, 60_000_000, 1);
+        assert_eq!(h.summary(), (1, 10_000.0, 10_000.0, 10_000.0));
+
+        let h = Histogram::new();
+        record_us(&h, 10_000_000, 98);
+        record_us(&h, 10_000_001, 2);
+        assert_eq!(h.summary(), (100, 10_000.0, 10_000.0, 10_000.0));
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::hash::BuildHasher;
+
+use parking_lot::Mutex;
+
+// 每个分片至少跟踪的条目数，分片过小会让近似计数的误差变大
+const MIN_SHARD_CAPACITY: usize = 16;
+// 条目只保留前若干字节：UA/Host 由客户端控制，不截断时每个计数器都可能占用整个请求头
+const MAX_ITEM_LEN: usize = 256;
+
+struct Counter {
+    item: String,
+    hash: u64,
+    count: u64,
+    error: u64,
+}
+
+/// Space-Saving 近似计数：最多跟踪 `capacity` 个条目，内存固定。
+///
+/// 表满时新条目替换计数最小的条目，并继承其计数作为误差上界，
+/// 因此真实次数落在 `[count - error, count]` 之间，高频条目不会被挤出。
+/// 条目按计数组成最小堆，命中只需下沉、替换直接取堆顶，均为 O(log n)；
+/// 被替换条目的字符串缓冲区原地复用，表满后不再分配内存。
+struct SpaceSaving {
+    capacity: usize,
+    heap: Vec<Counter>,
+    // 条目哈希 -> 堆下标
+    index: HashMap<u64, usize>,
+}
+
+impl SpaceSaving {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            heap: Vec::with_capacity(capacity),
+            index: HashMap::with_capacity(capacity),
+        }
+    }
+
+    fn record(&mut self, hash: u64, item: &str) {
+        if let Some(&i) = self.index.get(&hash) {
+            self.heap[i].count += 1;
+            self.sift_down(i);
+            return;
+        }
+        if self.heap.len() < self.capacity {
+            let i = self.heap.len();
+            self.heap.push(Counter { item: item.to_string(), hash, count: 1, error: 0 });
+            self.index.insert(hash, i);
+            self.sift_up(i);
+            return;
+        }
+        let Some(min) = self.heap.first_mut() else {
+            return;
+        };
+        self.index.remove(&min.hash);
+        min.item.clear();
+        min.item.push_str(item);
+        min.hash = hash;
+        min.error = min.count;
+        min.count += 1;
+        self.index.insert(hash, 0);
+        self.sift_down(0);
+    }
+
+    fn sift_up(&mut self, mut i: usize) {
+        while i > 0 {
+            let parent = (i - 1) / 2;
+            if self.heap[parent].count <= self.heap[i].count {
+                break;
+            }
+            self.swap(i, parent);
+            i = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut i: usize) {
+        loop {
+            let mut min = i;
+            for child in [2 * i + 1, 2 * i + 2] {
+                if child < self.heap.len() && self.heap[child].count < self.heap[min].count {
+                    min = child;
+                }
+            }
+            if min == i {
+                break;
+            }
+            self.swap(i, min);
+            i = min;
+        }
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.heap.swap(a, b);
+        self.index.insert(self.heap[a].hash, a);
+        self.index.insert(self.heap[b].hash, b);
+    }
+
+    /// 按计数降序取前 n 个 (条目, 计数, 误差)
+    fn top(&self, n: usize) -> Vec<(String, u64, u64)> {
+        let mut items: Vec<(String, u64, u64)> = self
+            .heap
+            .iter()
+            .map(|c| (c.item.clone(), c.count, c.error))
+            .collect();
+        sort_top(&mut items, n);
+        items
+    }
+
+    fn clear(&mut self) {
+        self.heap.clear();
+        self.index.clear();
+    }
+}
+
+fn sort_top(items: &mut Vec<(String, u64, u64)>, n: usize) {
+    items.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    items.truncate(n);
+}
+
+/// 按条目哈希分片的 Space-Saving，同一条目总落在同一分片，各分片独立加锁
+struct Tracker {
+    shards: Box<[Mutex<SpaceSaving>]>,
+}
+
+impl Tracker {
+    fn new(capacity: usize, shards: usize) -> Self {
+        let per_shard = capacity.div_ceil(shards);
+        Self {
+            shards: (0..shards).map(|_| Mutex::new(SpaceSaving::new(per_shard))).collect(),
+        }
+    }
+
+    fn record(&self, hash: u64, item: &str) {
+        // 分片数为 2 的幂，取哈希高位选分片，低位留给分片内的索引
+        let idx = (hash >> 32) as usize & (self.shards.len() - 1);
+        self.shards[idx].lock().record(hash, item);
+    }
+
+    fn top(&self, n: usize) -> Vec<(String, u64, u64)> {
+        let mut items: Vec<(String, u64, u64)> =
+            self.shards.iter().flat_map(|s| s.lock().top(n)).collect();
+        sort_top(&mut items, n);
+        items
+    }
+
+    fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.lock().clear();
+        }
+    }
+}
+
+/// 高频条目报告：原始 UA（区分是否被改写）、Host、客户端 IP、卸载条目
+pub struct TopN {
+    enabled: bool,
+    // 带随机种子的 SipHash，客户端无法构造碰撞把别的条目顶掉
+    hasher: RandomState,
+    ua_modified: Tracker,
+    ua_passed: Tracker,
+    hosts: Tracker,
+    clients: Tracker,
+    offloads: Tracker,
+}
+
+impl TopN {
+    /// capacity 为每类最多跟踪的条目数，0 表示禁用
+    pub fn new(capacity: usize) -> Self {
+        let shards = shard_count(capacity);
+        let tracker = || Tracker::new(capacity, shards);
+        Self {
+            enabled: capacity > 0,
+            hasher: RandomState::new(),
+            ua_modified: tracker(),
+            ua_passed: tracker(),
+            hosts: tracker(),
+            clients: tracker(),
+            offloads: tracker(),
+        }
+    }
+
+    pub fn record_ua(&self, ua: &str, modified: bool) {
+        if !self.enabled {
+            return;
+        }
+        if modified {
+            self.record(&self.ua_modified, ua);
+        } else {
+            self.record(&self.ua_passed, ua);
+        }
+    }
+
+    pub fn record_host(&self, host: &str) {
+        if self.enabled {
+            self.record(&self.hosts, host);
+        }
+    }
+
+    /// 禁用时不格式化，调用方可以直接传入地址
+    pub fn record_client(&self, client: impl fmt::Display) {
+        if self.enabled {
+            self.record(&self.clients, &client.to_string());
+        }
+    }
+
+    pub fn record_offload(&self, key: impl fmt::Display) {
+        if self.enabled {
+            self.record(&self.offloads, &key.to_string());
+        }
+    }
+
+    pub fn clear(&self) {
+        for t in self.trackers() {
+            t.1.clear();
+        }
+    }
+
+    /// 每类一个 `# 名称` 段落，每行 `<次数> <误差> <条目>`
+    pub fn render(&self, n: usize) -> String {
+        let mut out = String::new();
+        for (name, tracker) in self.trackers() {
+            let _ = writeln!(out, "# {name}");
+            for (item, count, error) in tracker.top(n) {
+                let _ = writeln!(out, "{count} {error} {item}");
+            }
+        }
+        out
+    }
+
+    /// 截断后再哈希，前缀相同的超长条目合并计数
+    fn record(&self, tracker: &Tracker, item: &str) {
+        let item = truncate(item, MAX_ITEM_LEN);
+        tracker.record(self.hasher.hash_one(item), item);
+    }
+
+    fn trackers(&self) -> [(&'static str, &Tracker); 5] {
+        [
+            ("ua_modified", &self.ua_modified),
+            ("ua_passed", &self.ua_passed),
+            ("hosts", &self.hosts),
+            ("clients", &self.clients),
+            ("offloads", &self.offloads),
+        ]
+    }
+}
+
+/// 截断到不超过 max 字节，且不切开多字节字符
+fn truncate(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// 每核一个分片（向上取整为 2 的幂），且每个分片不少于 MIN_SHARD_CAPACITY 个条目；
+/// 单核路由器上保持单分片
+fn shard_count(capacity: usize) -> usize {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut shards = cores.next_power_of_two();
+    while shards > 1 && capacity / shards < MIN_SHARD_CAPACITY {
+        shards /= 2;
+    }
+    shards
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 堆性质与索引一致
+    fn check(s: &SpaceSaving) {
+        for (i, c) in s.heap.iter().enumerate() {
+            assert_eq!(s.index[&c.hash], i);
+            if i > 0 {
+                assert!(s.heap[(i - 1) / 2].count <= c.count);
+            }
+        }
+        assert_eq!(s.index.len(), s.heap.len());
+    }
+
+    #[test]
+    fn exact_below_capacity() {
+        let hasher = RandomState::new();
+        let mut s = SpaceSaving::new(8);
+        for (item, n) in [("a", 5), ("b", 3), ("c", 1)] {
+            for _ in 0..n {
+                s.record(hasher.hash_one(item), item);
+            }
+        }
+        check(&s);
+        let top: Vec<(String, u64, u64)> = s.top(2);
+        assert_eq!(top, vec![("a".to_string(), 5, 0), ("b".to_string(), 3, 0)]);
+    }
+
+    #[test]
+    fn heavy_hitters_survive_churn() {
+        let hasher = RandomState::new();
+        let mut s = SpaceSaving::new(4);
+        for i in 0..1000 {
+            for heavy in ["x", "y"] {
+                s.record(hasher.hash_one(heavy), heavy);
+            }
+            let rare = format!("rare-{i}");
+            s.record(hasher.hash_one(&rare), &rare);
+            check(&s);
+        }
+        let top = s.top(2);
+        assert_eq!(top.iter().map(|t| t.0.as_str()).collect::<Vec<_>>(), ["x", "y"]);
+        // 真实次数落在 [count - error, count] 内
+        for (_, count, error) in top {
+            assert!(count - error <= 1000 && 1000 <= count);
+        }
+        assert_eq!(s.heap.len(), 4);
+    }
+
+    #[test]
+    fn replacement_inherits_min_count() {
+        let hasher = RandomState::new();
+        let mut s = SpaceSaving::new(2);
+        for item in ["a", "a", "b", "c"] {
+            s.record(hasher.hash_one(item), item);
+        }
+        check(&s);
+        assert_eq!(s.top(2), vec![("a".to_string(), 2, 0), ("c".to_string(), 2, 1)]);
+    }
+
+    #[test]
+    fn zero_capacity_tracks_nothing() {
+        let mut s = SpaceSaving::new(0);
+        s.record(RandomState::new().hash_one("a"), "a");
+        assert!(s.top(10).is_empty());
+    }
+
+    #[test]
+    fn render_merges_shards() {
+        let top = TopN::new(64);
+        for (host, n) in [("a.example", 3), ("b.example", 2), ("c.example", 1)] {
+            for _ in 0..n {
+                top.record_host(host);
+            }
+        }
+        top.record_client(std::net::Ipv4Addr::new(192, 168, 1, 10));
+        let out = top.render(2);
+        assert!(out.contains("# hosts\n3 0 a.example\n2 0 b.example\n# clients\n1 0 192.168.1.10\n"), "{out}");
+
+        top.clear();
+        assert!(!top.render(2).contains("example"));
+    }
+
+    #[test]
+    fn long_items_are_truncated() {
+        let top = TopN::new(64);
+        // 255 个 ASCII 字节之后是 3 字节字符，截断点落在字符中间时退回到字符边界
+        let prefix = "a".repeat(MAX_ITEM_LEN - 1);
+        top.record_ua(&format!("{prefix}中-1"), false);
+        top.record_ua(&format!("{prefix}中-2"), false);
+        let out = top.render(10);
+        assert!(out.contains(&format!("# ua_passed\n2 0 {prefix}\n#")), "{out}");
+
+        assert_eq!(truncate("short", MAX_ITEM_LEN), "short");
+        assert_eq!(truncate("ab中", 4), "ab");
+        assert_eq!(truncate("ab中", 5), "ab中");
+    }
+
+    #[test]
+    fn shards_keep_minimum_capacity() {
+        assert_eq!(shard_count(1), 1);
+        assert!(100 / shard_count(100) >= MIN_SHARD_CAPACITY);
+        assert!(shard_count(100_000).is_power_of_two());
+    }
+}
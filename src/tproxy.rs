@@ -9,6 +9,23 @@ const SO_ORIGINAL_DST: i32 = 80;
 // SOL_IP is 0 on Linux.
 const SOL_IP: i32 = 0;
 
+// SOL_SOCKET / SO_LINGER 在 MIPS 上取值不同，SO_MARK 各架构一致 (Linux)
+#[cfg(any(target_arch = "mips", target_arch = "mips64"))]
+const SOL_SOCKET: i32 = 0xffff;
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+const SOL_SOCKET: i32 = 1;
+#[cfg(any(target_arch = "mips", target_arch = "mips64"))]
+const SO_LINGER: i32 = 0x0080;
+#[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+const SO_LINGER: i32 = 13;
+const SO_MARK: i32 = 36;
+
+#[repr(C)]
+struct Linger {
+    l_onoff: i32,
+    l_linger: i32,
+}
+
 #[repr(C)]
 struct InAddr {
     s_addr: u32,
@@ -30,6 +47,14 @@ extern "C" {
         optval: *mut core::ffi::c_void,
         optlen: *mut u32,
     ) -> i32;
+
+    fn setsockopt(
+        sockfd: i32,
+        level: i32,
+        optname: i32,
+        optval: *const core::ffi::c_void,
+        optlen: u32,
+    ) -> i32;
 }
 
 pub fn original_dst(stream: &impl AsRawFd) -> io::Result<SocketAddrV4> {
@@ -69,3 +94,38 @@ pub fn original_dst_tokio(stream: &tokio::net::TcpStream) -> io::Result<SocketAd
     original_dst(stream)
 }
 
+
+/// 设置 SO_MARK（fwmark），供策略路由和防火墙识别本程序发出的连接。需要 CAP_NET_ADMIN。
+pub fn set_mark(socket: &impl AsRawFd, mark: u32) -> io::Result<()> {
+    let rc = unsafe {
+        setsockopt(
+            socket.as_raw_fd(),
+            SOL_SOCKET,
+            SO_MARK,
+            &mark as *const u32 as *const core::ffi::c_void,
+            size_of::<u32>() as u32,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// 设置 SO_LINGER 为 0：关闭 socket 时直接发送 RST，而不是正常的 FIN 挥手
+pub fn set_zero_linger(socket: &impl AsRawFd) -> io::Result<()> {
+    let linger = Linger { l_onoff: 1, l_linger: 0 };
+    let rc = unsafe {
+        setsockopt(
+            socket.as_raw_fd(),
+            SOL_SOCKET,
+            SO_LINGER,
+            &linger as *const Linger as *const core::ffi::c_void,
+            size_of::<Linger>() as u32,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}